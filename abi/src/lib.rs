@@ -0,0 +1,210 @@
+//! Definitions shared by the kernel and user programs.
+#![no_std]
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_YIELD: usize = 1;
+pub const SYS_GETPID: usize = 2;
+pub const SYS_SLEEP: usize = 3;
+pub const SYS_WRITE: usize = 4;
+pub const SYS_SEND: usize = 5;
+pub const SYS_RECV: usize = 6;
+pub const SYS_BRK: usize = 7;
+pub const SYS_SPAWN: usize = 8;
+pub const SYS_EXEC: usize = 9;
+pub const SYS_SCHED_SET: usize = 10;
+pub const SYS_THREAD_CREATE: usize = 11;
+pub const SYS_THREAD_EXIT: usize = 12;
+pub const SYS_THREAD_JOIN: usize = 13;
+pub const SYS_SBRK: usize = 14;
+pub const SYS_GETRLIMIT: usize = 15;
+pub const SYS_SETRLIMIT: usize = 16;
+pub const SYS_WATCHDOG: usize = 17;
+pub const SYS_CHECKPOINT: usize = 18;
+pub const SYS_RESTORE: usize = 19;
+pub const SYS_FUTEX: usize = 20;
+pub const SYS_SET_AFFINITY: usize = 21;
+pub const SYS_SHM_ATTACH: usize = 22;
+pub const SYS_SHM_DETACH: usize = 23;
+pub const SYSCALL_COUNT: usize = 24;
+
+// most arguments SYS_SPAWN and SYS_EXEC accept
+pub const ARGV_MAX: usize = 16;
+
+// scheduling classes for SYS_SCHED_SET: the parameters are the MLFQ priority level (0 highest),
+// the fair-share weight, or the real-time period, budget and deadline in microseconds
+pub const SCHED_MLFQ: usize = 0;
+pub const SCHED_FAIR: usize = 1;
+pub const SCHED_EDF: usize = 2;
+
+// resources for SYS_GETRLIMIT and SYS_SETRLIMIT: user pages, CPU time in microseconds, live
+// child processes, messages queued for the process and pages of shared memory attached
+pub const RLIMIT_PAGES: usize = 0;
+pub const RLIMIT_CPU: usize = 1;
+pub const RLIMIT_CHILDREN: usize = 2;
+pub const RLIMIT_IPC_QUEUE: usize = 3;
+pub const RLIMIT_SHM: usize = 4;
+// no limit
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+// what SYS_WATCHDOG makes the watchdog do to a process that stops making progress, on top of
+// reporting it
+pub const WATCHDOG_REPORT: usize = 0;
+pub const WATCHDOG_KILL: usize = 1;
+pub const WATCHDOG_RESTART: usize = 2;
+
+// SYS_FUTEX operations: wait while the word still holds a value, optionally with a timeout in
+// microseconds, or wake up to a number of waiters
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+// returned negated in a0
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const ESRCH: isize = 3;
+pub const EIO: isize = 5;
+pub const E2BIG: isize = 7;
+pub const ENOEXEC: isize = 8;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EFAULT: isize = 14;
+pub const EBUSY: isize = 16;
+pub const EINVAL: isize = 22;
+pub const ERANGE: isize = 34;
+pub const EDEADLK: isize = 35;
+pub const ENOSYS: isize = 38;
+pub const ETIMEDOUT: isize = 110;
+
+// frequency of the `time` CSR, readable from U-mode with rdtime
+pub const TIMEBASE_FREQ: u64 = 10_000_000; // 10 MHz
+
+// raw pids and thread ids carry the slot index in the low bits and the slot generation above
+pub const PID_INDEX_BITS: usize = 16;
+
+// `src` argument of SYS_RECV accepting a message from any process
+pub const SRC_ANY: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Message {
+    Ping,
+    Data {
+        a: usize,
+        b: usize,
+    },
+
+    DisplayPrint {
+        display: u8, // 0..3
+        line: u8,
+        text: [u8; 32],
+        len: u8,
+    },
+    DisplayClear(u8), // 0..3
+    DisplayDrawCell {
+        display: u8,
+        x: u8,
+        y: u8,
+        fg: u8,
+        bg: u8,
+        ch: char,
+    },
+}
+
+// Messages cross the syscall boundary as this many words
+pub const MESSAGE_WORDS: usize = 10;
+
+const TAG_PING: usize = 0;
+const TAG_DATA: usize = 1;
+const TAG_DISPLAY_PRINT: usize = 2;
+const TAG_DISPLAY_CLEAR: usize = 3;
+const TAG_DISPLAY_DRAW_CELL: usize = 4;
+
+fn pack(bytes: [u8; 4]) -> usize {
+    u32::from_le_bytes(bytes) as usize
+}
+
+fn unpack(word: usize) -> [u8; 4] {
+    (word as u32).to_le_bytes()
+}
+
+impl Message {
+    pub fn encode(&self) -> [usize; MESSAGE_WORDS] {
+        let mut words = [0; MESSAGE_WORDS];
+        match *self {
+            Message::Ping => words[0] = TAG_PING,
+            Message::Data { a, b } => {
+                words[0] = TAG_DATA;
+                words[1] = a;
+                words[2] = b;
+            }
+            Message::DisplayPrint {
+                display,
+                line,
+                text,
+                len,
+            } => {
+                words[0] = TAG_DISPLAY_PRINT;
+                words[1] = pack([display, line, len, 0]);
+                for (word, chunk) in words[2..].iter_mut().zip(text.chunks(4)) {
+                    *word = pack([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+            Message::DisplayClear(display) => {
+                words[0] = TAG_DISPLAY_CLEAR;
+                words[1] = display as usize;
+            }
+            Message::DisplayDrawCell {
+                display,
+                x,
+                y,
+                fg,
+                bg,
+                ch,
+            } => {
+                words[0] = TAG_DISPLAY_DRAW_CELL;
+                words[1] = pack([display, x, y, fg]);
+                words[2] = bg as usize;
+                words[3] = ch as usize;
+            }
+        }
+        words
+    }
+
+    pub fn decode(words: &[usize; MESSAGE_WORDS]) -> Option<Message> {
+        let msg = match words[0] {
+            TAG_PING => Message::Ping,
+            TAG_DATA => Message::Data {
+                a: words[1],
+                b: words[2],
+            },
+            TAG_DISPLAY_PRINT => {
+                let [display, line, len, _] = unpack(words[1]);
+                if len > 32 {
+                    return None;
+                }
+                let mut text = [0; 32];
+                for (chunk, word) in text.chunks_mut(4).zip(&words[2..]) {
+                    chunk.copy_from_slice(&unpack(*word));
+                }
+                Message::DisplayPrint {
+                    display,
+                    line,
+                    text,
+                    len,
+                }
+            }
+            TAG_DISPLAY_CLEAR => Message::DisplayClear(words[1] as u8),
+            TAG_DISPLAY_DRAW_CELL => {
+                let [display, x, y, fg] = unpack(words[1]);
+                Message::DisplayDrawCell {
+                    display,
+                    x,
+                    y,
+                    fg,
+                    bg: words[2] as u8,
+                    ch: char::from_u32(words[3] as u32)?,
+                }
+            }
+            _ => return None,
+        };
+        Some(msg)
+    }
+}
@@ -0,0 +1,44 @@
+use std::{env, path::PathBuf, process::Command};
+
+const TARGET: &str = "riscv32i-unknown-none-elf";
+
+fn main() {
+    // set here rather than in .cargo/config.toml so that they don't leak into the user programs
+    println!("cargo:rustc-link-arg-bin=kernel=-Tkernel.ld");
+    println!("cargo:rustc-link-arg-bin=kernel=-Map=kernel.map");
+    println!("cargo:rerun-if-changed=kernel.ld");
+
+    build_user_programs();
+}
+
+fn build_user_programs() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let user_dir = manifest_dir.join("user");
+    let target_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("user");
+
+    for path in ["src", "Cargo.toml", "user.ld", ".cargo/config.toml"] {
+        println!("cargo:rerun-if-changed={}", user_dir.join(path).display());
+    }
+    println!(
+        "cargo:rerun-if-changed={}",
+        manifest_dir.join("abi").display()
+    );
+
+    let status = Command::new(env::var("CARGO").unwrap())
+        .current_dir(&user_dir)
+        .args(["build", "--release", "--target", TARGET, "--target-dir"])
+        .arg(&target_dir)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_TARGET_DIR")
+        .status()
+        .expect("failed to run cargo for the user programs");
+    if !status.success() {
+        panic!("failed to build the user programs");
+    }
+
+    println!(
+        "cargo:rustc-env=USER_BIN_DIR={}",
+        target_dir.join(TARGET).join("release").display()
+    );
+}
@@ -0,0 +1,114 @@
+// The parts of the kernel that are plain logic, built for the host so that their unit tests run
+// with `cargo test`. What they use of the rest of the kernel is stubbed out below.
+#![feature(pointer_is_aligned_to)]
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../src/constants.rs"]
+mod constants;
+#[path = "../../src/process/pid.rs"]
+mod pid;
+#[path = "../../src/sched/mod.rs"]
+mod sched;
+#[path = "../../src/shm.rs"]
+mod shm;
+#[path = "../../src/utils.rs"]
+mod utils;
+
+mod process {
+    pub use crate::pid::{Pid, Tid};
+    use crate::utils::PhysAddr;
+
+    #[derive(Clone, Copy)]
+    pub struct Limits {
+        pub shm: usize,
+    }
+
+    pub struct ProcessManager;
+
+    pub static PM: ProcessManager = ProcessManager;
+
+    impl ProcessManager {
+        pub fn current_pid(&self) -> Pid {
+            unimplemented!()
+        }
+
+        pub fn page_table(&self, _pid: Pid) -> PhysAddr {
+            unimplemented!()
+        }
+
+        pub fn limits(&self, _pid: Pid) -> Option<Limits> {
+            unimplemented!()
+        }
+
+        pub fn flush_tlb(&self, _pid: Pid, _vaddr: usize, _len: usize) {
+            unimplemented!()
+        }
+    }
+}
+
+mod memory {
+    use crate::utils::{PhysAddr, VirtAddr};
+
+    pub fn alloc_user_page() -> Option<PhysAddr> {
+        unimplemented!()
+    }
+
+    pub fn free_page(_paddr: PhysAddr) {
+        unimplemented!()
+    }
+
+    pub fn map_page(
+        _page_table: PhysAddr,
+        _vaddr: VirtAddr,
+        _paddr: PhysAddr,
+        _flags: u32,
+    ) -> bool {
+        unimplemented!()
+    }
+
+    pub fn unmap_page(_page_table: PhysAddr, _vaddr: VirtAddr) -> Option<PhysAddr> {
+        unimplemented!()
+    }
+}
+
+mod sbi {
+    #[allow(clippy::too_many_arguments)]
+    pub fn sbi_call(
+        _arg0: usize,
+        _arg1: usize,
+        _arg2: usize,
+        _arg3: usize,
+        _arg4: usize,
+        _arg5: usize,
+        _fid: usize,
+        _eid: usize,
+    ) -> Result<usize, isize> {
+        unimplemented!()
+    }
+}
+
+mod sync {
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    pub struct SpinLock<T>(Mutex<T>);
+
+    impl<T> SpinLock<T> {
+        pub const fn new(value: T) -> Self {
+            SpinLock(Mutex::new(value))
+        }
+
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+}
+
+mod timer {
+    use abi::TIMEBASE_FREQ;
+
+    pub const fn us_to_ticks(us: u64) -> u64 {
+        us * TIMEBASE_FREQ / 1_000_000
+    }
+}
@@ -1,2 +0,0 @@
-pub mod display;
-pub mod playground;
@@ -0,0 +1,325 @@
+use alloc::vec::Vec;
+use core::{ptr, slice};
+
+use abi::MESSAGE_WORDS;
+
+use crate::{
+    constants::{
+        PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, PAGE_X, PROC_NAME_MAX, PROC_THREADS_MAX, USER_BASE,
+        USER_HEAP_BASE, USER_STACK_TOP,
+    },
+    ipc::{Message, SenderEntry},
+    memory::{
+        copy_from_user, copy_to_user, count_user_pages, for_each_user_page, free_page_table,
+        is_user_range, map_user_page, new_page_table, translate,
+    },
+    process::{Name, PM, Pid},
+    trap_handler::TrapFrame,
+    utils::{Addr, PhysAddr, VirtAddr},
+};
+
+// The blob is a header, the user registers of each thread, the messages queued for the process
+// with their senders and then every user page with its address and permissions. All words are
+// little-endian usize.
+//
+// A thread blocked in send or recv is saved with sepc on its ecall, so it makes the call again
+// once restored. The senders blocked on the process stay blocked on the original; the restored
+// process gets copies of their messages.
+const MAGIC: usize = 0x5450_4b43; // "CKPT"
+const VERSION: usize = 2;
+
+const WORD: usize = size_of::<usize>();
+// magic, version, name length, entry, brk, heap_mapped, inbox flag, thread, sender and page count
+const HEADER_WORDS: usize = 10 + MESSAGE_WORDS;
+const HEADER_SIZE: usize = HEADER_WORDS * WORD + PROC_NAME_MAX;
+const FRAME_SIZE: usize = size_of::<TrapFrame>();
+const SENDER_RECORD_SIZE: usize = (1 + MESSAGE_WORDS) * WORD;
+const PAGE_RECORD_SIZE: usize = 2 * WORD + PAGE_SIZE;
+
+const PAGE_FLAGS: u32 = PAGE_R | PAGE_W | PAGE_X | PAGE_U;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    NoSuchProcess,
+    BufferTooSmall,
+    BadAddress,
+    // not a blob this kernel wrote, or one that makes no sense
+    Corrupt,
+    // more pages than the process restoring it is allowed
+    PageLimit,
+    // more queued messages than the process restoring it is allowed
+    QueueLimit,
+    // more than the memory left
+    OutOfMemory,
+    // a thread of the process is on another hart
+    Running,
+    // no free slot in the process or thread table
+    TableFull,
+}
+
+// A process as the kernel sees it, taken with `ProcessManager::checkpoint`
+pub struct Checkpoint {
+    pub name: Name,
+    pub entry: usize,
+    pub brk: usize,
+    pub heap_mapped: usize,
+    pub page_table: PhysAddr,
+    // a message handed to a recv that has not returned yet
+    pub inbox: Option<Message>,
+    pub senders: Vec<SenderEntry>,
+    // one per live thread
+    pub frames: Vec<TrapFrame>,
+}
+
+fn blob_size(threads: usize, senders: usize, pages: usize) -> Option<usize> {
+    let frames = threads.checked_mul(FRAME_SIZE)?;
+    let senders = senders.checked_mul(SENDER_RECORD_SIZE)?;
+    let pages = pages.checked_mul(PAGE_RECORD_SIZE)?;
+    HEADER_SIZE
+        .checked_add(frames)?
+        .checked_add(senders)?
+        .checked_add(pages)
+}
+
+// Walks through a buffer of the current process
+struct Cursor {
+    page_table: PhysAddr,
+    addr: usize,
+    end: usize,
+}
+
+impl Cursor {
+    fn new(addr: usize, len: usize) -> Self {
+        Cursor {
+            page_table: PM.current_page_table(),
+            addr,
+            end: addr + len,
+        }
+    }
+
+    fn advance(&mut self, len: usize) -> Result<VirtAddr, CheckpointError> {
+        if self.end - self.addr < len {
+            return Err(CheckpointError::Corrupt);
+        }
+        let addr = VirtAddr::from_usize(self.addr);
+        self.addr += len;
+        Ok(addr)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), CheckpointError> {
+        let dst = self.advance(bytes.len())?;
+        if !copy_to_user(self.page_table, dst, bytes) {
+            return Err(CheckpointError::BadAddress);
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, word: usize) -> Result<(), CheckpointError> {
+        self.write(&word.to_le_bytes())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), CheckpointError> {
+        let src = self.advance(buf.len())?;
+        if !copy_from_user(self.page_table, src, buf) {
+            return Err(CheckpointError::BadAddress);
+        }
+        Ok(())
+    }
+
+    fn read_word(&mut self) -> Result<usize, CheckpointError> {
+        let mut bytes = [0; WORD];
+        self.read(&mut bytes)?;
+        Ok(usize::from_le_bytes(bytes))
+    }
+}
+
+// Writes `pid` out to the `len` bytes at `buf` in the current process and returns the size of
+// the blob. A `len` of 0 only asks for the size.
+pub fn save(pid: Pid, buf: usize, len: usize) -> Result<usize, CheckpointError> {
+    let checkpoint = PM.checkpoint(pid)?;
+
+    let mut pages = Vec::new();
+    for_each_user_page(checkpoint.page_table, |vaddr, paddr, pte| {
+        pages.push((vaddr, paddr, pte & PAGE_FLAGS))
+    });
+
+    let size = blob_size(
+        checkpoint.frames.len(),
+        checkpoint.senders.len(),
+        pages.len(),
+    )
+    .ok_or(CheckpointError::Corrupt)?;
+    if len == 0 {
+        return Ok(size);
+    }
+    if len < size {
+        return Err(CheckpointError::BufferTooSmall);
+    }
+
+    let mut out = Cursor::new(buf, len);
+    out.write_word(MAGIC)?;
+    out.write_word(VERSION)?;
+
+    let name = checkpoint.name.as_str().as_bytes();
+    let mut name_buf = [0; PROC_NAME_MAX];
+    name_buf[..name.len()].copy_from_slice(name);
+    out.write_word(name.len())?;
+    out.write(&name_buf)?;
+
+    out.write_word(checkpoint.entry)?;
+    out.write_word(checkpoint.brk)?;
+    out.write_word(checkpoint.heap_mapped)?;
+
+    out.write_word(checkpoint.inbox.is_some() as usize)?;
+    let inbox = checkpoint
+        .inbox
+        .map_or([0; MESSAGE_WORDS], |msg| msg.encode());
+    for word in inbox {
+        out.write_word(word)?;
+    }
+
+    out.write_word(checkpoint.frames.len())?;
+    out.write_word(checkpoint.senders.len())?;
+    out.write_word(pages.len())?;
+
+    for frame in &checkpoint.frames {
+        let bytes =
+            unsafe { slice::from_raw_parts(frame as *const TrapFrame as *const u8, FRAME_SIZE) };
+        out.write(bytes)?;
+    }
+
+    for entry in &checkpoint.senders {
+        out.write_word(entry.src.as_raw())?;
+        for word in entry.msg.encode() {
+            out.write_word(word)?;
+        }
+    }
+
+    for (vaddr, paddr, flags) in pages {
+        out.write_word(vaddr.as_usize())?;
+        out.write_word(flags as usize)?;
+        out.write(unsafe { slice::from_raw_parts(paddr.as_ptr(), PAGE_SIZE) })?;
+    }
+
+    Ok(size)
+}
+
+// Reads a blob from the `len` bytes at `buf` in the current process and starts it as a new
+// child of it
+pub fn restore(buf: usize, len: usize) -> Result<Pid, CheckpointError> {
+    let mut input = Cursor::new(buf, len);
+    if input.read_word()? != MAGIC || input.read_word()? != VERSION {
+        return Err(CheckpointError::Corrupt);
+    }
+
+    let name_len = input.read_word()?;
+    let mut name_buf = [0; PROC_NAME_MAX];
+    input.read(&mut name_buf)?;
+    let name = name_buf
+        .get(..name_len)
+        .and_then(|name| str::from_utf8(name).ok())
+        .ok_or(CheckpointError::Corrupt)?;
+
+    let entry = input.read_word()?;
+    let brk = input.read_word()?;
+    let heap_mapped = input.read_word()?;
+    if brk < USER_HEAP_BASE || heap_mapped < brk || heap_mapped % PAGE_SIZE != 0 {
+        return Err(CheckpointError::Corrupt);
+    }
+
+    let has_inbox = input.read_word()? != 0;
+    let mut words = [0; MESSAGE_WORDS];
+    for word in words.iter_mut() {
+        *word = input.read_word()?;
+    }
+    let inbox = if has_inbox {
+        Some(Message::decode(&words).ok_or(CheckpointError::Corrupt)?)
+    } else {
+        None
+    };
+
+    let threads = input.read_word()?;
+    let senders = input.read_word()?;
+    let pages = input.read_word()?;
+    let size = blob_size(threads, senders, pages).ok_or(CheckpointError::Corrupt)?;
+    if threads == 0 || threads > PROC_THREADS_MAX || size > len {
+        return Err(CheckpointError::Corrupt);
+    }
+    let limits = PM
+        .limits(PM.current_pid())
+        .ok_or(CheckpointError::NoSuchProcess)?;
+    if pages > limits.pages {
+        return Err(CheckpointError::PageLimit);
+    }
+    if senders > limits.ipc_queue {
+        return Err(CheckpointError::QueueLimit);
+    }
+    if !is_user_range(input.page_table, VirtAddr::from_usize(buf), size) {
+        return Err(CheckpointError::BadAddress);
+    }
+
+    let mut frames = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let mut bytes = [0; FRAME_SIZE];
+        input.read(&mut bytes)?;
+        frames.push(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const TrapFrame) });
+    }
+
+    let mut queued = Vec::with_capacity(senders);
+    for _ in 0..senders {
+        let src = Pid::from_raw(input.read_word()?);
+        for word in words.iter_mut() {
+            *word = input.read_word()?;
+        }
+        let msg = Message::decode(&words).ok_or(CheckpointError::Corrupt)?;
+        queued.push(SenderEntry {
+            src,
+            msg,
+            waiting: false,
+        });
+    }
+
+    let page_table = new_page_table().ok_or(CheckpointError::OutOfMemory)?;
+    if let Err(err) = read_pages(&mut input, page_table, pages) {
+        free_page_table(page_table);
+        return Err(err);
+    }
+
+    let checkpoint = Checkpoint {
+        name: Name::new(name),
+        entry,
+        brk,
+        heap_mapped,
+        page_table,
+        inbox,
+        senders: queued,
+        frames,
+    };
+    PM.restore(&checkpoint, count_user_pages(page_table))
+}
+
+fn read_pages(
+    input: &mut Cursor,
+    page_table: PhysAddr,
+    pages: usize,
+) -> Result<(), CheckpointError> {
+    for _ in 0..pages {
+        let vaddr = VirtAddr::from_usize(input.read_word()?);
+        let flags = input.read_word()? as u32;
+        if !vaddr.is_aligned(PAGE_SIZE)
+            || !(USER_BASE..USER_STACK_TOP).contains(&vaddr.as_usize())
+            || flags & !PAGE_FLAGS != 0
+            || flags & PAGE_U == 0
+            || translate(page_table, vaddr).is_some()
+        {
+            return Err(CheckpointError::Corrupt);
+        }
+
+        if !map_user_page(page_table, vaddr, flags) {
+            return Err(CheckpointError::OutOfMemory);
+        }
+        let paddr = translate(page_table, vaddr).ok_or(CheckpointError::Corrupt)?;
+        input.read(unsafe { slice::from_raw_parts_mut(paddr.as_ptr_mut(), PAGE_SIZE) })?;
+    }
+    Ok(())
+}
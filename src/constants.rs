@@ -1,32 +1,100 @@
+// symbols from kernel.ld; only their addresses mean anything
 unsafe extern "C" {
     static __kernel_base: u8;
     static mut __bss: u8;
     static __bss_end: u8;
-    static __stack_top: u8;
     static mut __free_ram: u8;
     static __free_ram_end: u8;
 }
 
-pub static mut KERNEL_BASE: *const u8 = &raw const __kernel_base;
-pub static mut BSS: *mut u8 = &raw mut __bss;
-pub static mut BSS_END: *const u8 = &raw const __bss_end;
-pub static mut STACK_TOP: *const u8 = &raw const __stack_top;
-pub static mut FREE_RAM: *mut u8 = &raw mut __free_ram;
-pub static mut FREE_RAM_END: *const u8 = &raw const __free_ram_end;
+pub fn kernel_base() -> *const u8 {
+    &raw const __kernel_base
+}
+
+pub fn bss() -> *mut u8 {
+    &raw mut __bss
+}
+
+pub fn bss_end() -> *const u8 {
+    &raw const __bss_end
+}
+
+pub fn free_ram() -> *mut u8 {
+    &raw mut __free_ram
+}
+
+pub fn free_ram_end() -> *const u8 {
+    &raw const __free_ram_end
+}
 
 pub const PAGE_SIZE: usize = 4096;
 
 pub const SSTATUS_SIE: usize = 1 << 1;
+pub const SSTATUS_SPIE: usize = 1 << 5;
+pub const SSTATUS_SPP: usize = 1 << 8;
 pub const SATP_SV32: usize = 1 << 31;
 
+// lets U-mode read the time CSR
+pub const SCOUNTEREN_TM: usize = 1 << 1;
+
 pub const PAGE_V: u32 = 1 << 0;
 pub const PAGE_R: u32 = 1 << 1;
 pub const PAGE_W: u32 = 1 << 2;
 pub const PAGE_X: u32 = 1 << 3;
 pub const PAGE_U: u32 = 1 << 4;
 
-pub const KERNEL_STACK_SIZE: usize = 8192;
+pub const KERNEL_STACK_SIZE: usize = 8192; // in bytes, a whole number of pages
+
+// hart ids SBI may hand out, each of which gets an idle thread
+pub const MAX_HARTS: usize = 8;
+
+pub const USER_BASE: usize = 0x0100_0000;
+pub const USER_HEAP_BASE: usize = 0x2000_0000;
+// shared memory goes between the heap and the stack
+pub const USER_SHM_BASE: usize = 0x3000_0000;
+pub const USER_STACK_TOP: usize = 0x4000_0000;
+pub const USER_STACK_PAGES: usize = 4;
+
+pub const PROC_NAME_MAX: usize = 16;
+// threads a process may have at a time
+pub const PROC_THREADS_MAX: usize = 32;
+
+// free pages that address spaces may not take, so that kernel stacks and the kernel heap can
+// still grow once user processes have used up the rest
+pub const KERNEL_RESERVE_PAGES: usize = 1024; // 4 MiB
+
+// what the first process may use; everyone else starts with the limits of its parent
+pub const RLIMIT_PAGES: usize = 2048; // 8 MiB
+pub const RLIMIT_CHILDREN: usize = 16;
+// senders a receiver queues before further sends fail with SendQueueFull
+pub const RLIMIT_IPC_QUEUE: usize = 256;
+pub const RLIMIT_SHM: usize = 256; // 1 MiB
 
-pub const PROCS_MAX: usize = 8;
+pub const SCHED_LEVELS: usize = 4;
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_SLICE_US: u64 = 20_000; // 20 ms at the top level, doubled per level
+// longest the timer goes without interrupting the running thread. Slices are only checked on
+// interrupts, so this has to stay below SCHED_SLICE_US for a slice to end on time.
+pub const SCHED_TICK_US: u64 = 10_000; // 10 ms
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_BOOST_PERIOD_US: u64 = 2_000_000; // 2 seconds
+// permille of the CPU real-time processes may reserve together
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_RT_BANDWIDTH: u64 = 900;
+// permille of the CPU a single process may reserve, so that anybody allowed to go real-time
+// cannot take all of the above
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_RT_PROCESS_BANDWIDTH: u64 = 300;
+// permille of the CPU fair-class processes get together while MLFQ ones are runnable as well
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_FAIR_SHARE: u64 = 300;
+// weight of a fair-class process whose virtual runtime advances at wall-clock speed
+#[cfg(not(feature = "sched-fifo"))]
+pub const SCHED_WEIGHT_UNIT: u64 = 1024;
+// how often threads are spread evenly over the run queues of the harts
+pub const SCHED_BALANCE_PERIOD_US: u64 = 100_000; // 100 ms
+pub const LOADAVG_PERIOD_US: u64 = 5_000_000; // 5 seconds
 
-pub const TIMER_QUANTUM_US: u64 = 1_000_000; // 1 second
+pub const WATCHDOG_PERIOD_US: u64 = 1_000_000; // 1 second
+// how long a process may go without progress before the watchdog steps in
+pub const WATCHDOG_TIMEOUT_US: u64 = 5_000_000; // 5 seconds
@@ -0,0 +1,150 @@
+use crate::{
+    constants::{PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, PAGE_X, USER_BASE, USER_HEAP_BASE},
+    memory::{copy_to_user, map_user_page, translate},
+    utils::{Addr, PhysAddr, VirtAddr},
+};
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 0xf3;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[derive(Debug)]
+pub enum ElfError {
+    BadMagic,
+    Unsupported,
+    Truncated,
+    BadSegment,
+    ArgumentsTooLarge,
+    // the image and stack need more pages than the process is allowed
+    PageLimit,
+    // more than the memory left
+    OutOfMemory,
+    // no free slot in the process or thread table
+    TableFull,
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    p_offset: usize,
+    p_vaddr: usize,
+    p_filesz: usize,
+    p_memsz: usize,
+    p_flags: u32,
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = image.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = image.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+impl ProgramHeader {
+    fn parse(image: &[u8], offset: usize) -> Result<Self, ElfError> {
+        Ok(ProgramHeader {
+            p_type: read_u32(image, offset)?,
+            p_offset: read_u32(image, offset + 4)? as usize,
+            p_vaddr: read_u32(image, offset + 8)? as usize,
+            p_filesz: read_u32(image, offset + 16)? as usize,
+            p_memsz: read_u32(image, offset + 20)? as usize,
+            p_flags: read_u32(image, offset + 24)?,
+        })
+    }
+
+    fn page_flags(&self) -> u32 {
+        let mut flags = PAGE_U;
+        if self.p_flags & PF_R != 0 {
+            flags |= PAGE_R;
+        }
+        if self.p_flags & PF_W != 0 {
+            flags |= PAGE_W;
+        }
+        if self.p_flags & PF_X != 0 {
+            flags |= PAGE_X;
+        }
+        flags
+    }
+}
+
+// Maps the PT_LOAD segments of `image` into `page_table` and returns the entry point
+pub fn load(page_table: PhysAddr, image: &[u8]) -> Result<usize, ElfError> {
+    if image.get(..4) != Some(&ELF_MAGIC[..]) {
+        return Err(ElfError::BadMagic);
+    }
+    if image.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if image[4] != ELFCLASS32
+        || image[5] != ELFDATA2LSB
+        || read_u16(image, 16)? != ET_EXEC
+        || read_u16(image, 18)? != EM_RISCV
+    {
+        return Err(ElfError::Unsupported);
+    }
+
+    let entry = read_u32(image, 24)? as usize;
+    let phoff = read_u32(image, 28)? as usize;
+    let phentsize = read_u16(image, 42)? as usize;
+    let phnum = read_u16(image, 44)? as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+
+    for i in 0..phnum {
+        let phdr = ProgramHeader::parse(image, phoff + i * phentsize)?;
+        if phdr.p_type == PT_LOAD {
+            load_segment(page_table, image, &phdr)?;
+        }
+    }
+
+    Ok(entry)
+}
+
+fn load_segment(page_table: PhysAddr, image: &[u8], phdr: &ProgramHeader) -> Result<(), ElfError> {
+    let start = phdr.p_vaddr;
+    let end = start
+        .checked_add(phdr.p_memsz)
+        .ok_or(ElfError::BadSegment)?;
+    if phdr.p_filesz > phdr.p_memsz || start < USER_BASE || end > USER_HEAP_BASE {
+        return Err(ElfError::BadSegment);
+    }
+
+    let data = phdr
+        .p_offset
+        .checked_add(phdr.p_filesz)
+        .and_then(|data_end| image.get(phdr.p_offset..data_end))
+        .ok_or(ElfError::Truncated)?;
+
+    let mut vaddr = VirtAddr::from_usize(start - start % PAGE_SIZE);
+    while vaddr.as_usize() < end {
+        // segments are expected to be page-aligned, see user/user.ld
+        if translate(page_table, vaddr).is_some() {
+            return Err(ElfError::BadSegment);
+        }
+        if !map_user_page(page_table, vaddr, phdr.page_flags()) {
+            return Err(ElfError::OutOfMemory);
+        }
+        vaddr = VirtAddr::from_usize(vaddr.as_usize() + PAGE_SIZE);
+    }
+
+    // the rest of the segment (.bss) stays zero-filled by map_user_page
+    if !copy_to_user(page_table, VirtAddr::from_usize(start), data) {
+        return Err(ElfError::BadSegment);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::time::Duration;
+
+use crate::{
+    memory::copy_from_user,
+    process::{PM, Pid},
+    sync::SpinLock,
+    utils::{Addr, VirtAddr},
+    wait_queue::WaitQueue,
+};
+
+#[derive(Debug)]
+pub enum FutexError {
+    // the word no longer held the expected value
+    WouldBlock,
+    TimedOut,
+    BadAddress,
+}
+
+// A wait queue per word user space waits on, keyed by process and address, for as long as
+// someone waits there. Queues are never freed: one that empties goes to `spare` for the next word
+// that needs one.
+struct Futexes {
+    active: Vec<(Pid, usize, &'static WaitQueue)>,
+    spare: Vec<&'static WaitQueue>,
+}
+
+static FUTEXES: SpinLock<Futexes> = SpinLock::new(Futexes {
+    active: Vec::new(),
+    spare: Vec::new(),
+});
+
+fn find(pid: Pid, addr: usize) -> Option<&'static WaitQueue> {
+    FUTEXES
+        .lock()
+        .active
+        .iter()
+        .find(|(p, a, _)| *p == pid && *a == addr)
+        .map(|(_, _, queue)| *queue)
+}
+
+fn find_or_insert(pid: Pid, addr: usize) -> &'static WaitQueue {
+    let mut futexes = FUTEXES.lock();
+    if let Some((_, _, queue)) = futexes
+        .active
+        .iter()
+        .find(|(p, a, _)| *p == pid && *a == addr)
+    {
+        return queue;
+    }
+
+    let queue = futexes
+        .spare
+        .pop()
+        .unwrap_or_else(|| Box::leak(Box::new(WaitQueue::new())));
+    futexes.active.push((pid, addr, queue));
+    queue
+}
+
+// Gives the queue of `addr` back once nobody waits there any more
+fn release_if_empty(pid: Pid, addr: usize) {
+    let mut futexes = FUTEXES.lock();
+    let Some(index) = futexes
+        .active
+        .iter()
+        .position(|(p, a, queue)| *p == pid && *a == addr && queue.is_empty())
+    else {
+        return;
+    };
+    let (_, _, queue) = futexes.active.swap_remove(index);
+    futexes.spare.push(queue);
+}
+
+// Gives back every queue of `pid`, for a process that is gone or starts over. The threads still
+// queued there were torn down already.
+pub fn release(pid: Pid) {
+    let mut futexes = FUTEXES.lock();
+    let futexes = &mut *futexes;
+    futexes.active.retain(|(p, _, queue)| {
+        if *p != pid {
+            return true;
+        }
+        queue.clear();
+        futexes.spare.push(*queue);
+        false
+    });
+}
+
+fn load(addr: usize) -> Result<usize, FutexError> {
+    let mut bytes = [0; size_of::<usize>()];
+    if !copy_from_user(
+        PM.current_page_table(),
+        VirtAddr::from_usize(addr),
+        &mut bytes,
+    ) {
+        return Err(FutexError::BadAddress);
+    }
+    Ok(usize::from_le_bytes(bytes))
+}
+
+// Blocks the current thread until a wake on `addr`, unless the word there no longer holds
+// `expected`. Checking and queueing happen without a switch in between, so a wake that follows a
+// store to the word is never lost.
+pub fn wait(addr: usize, expected: usize, timeout: Option<Duration>) -> Result<(), FutexError> {
+    if load(addr)? != expected {
+        return Err(FutexError::WouldBlock);
+    }
+
+    let pid = PM.current_pid();
+    let queue = find_or_insert(pid, addr);
+    let woken = match timeout {
+        None => {
+            queue.wait();
+            true
+        }
+        Some(timeout) => queue.wait_timeout(timeout),
+    };
+    release_if_empty(pid, addr);
+
+    if !woken {
+        return Err(FutexError::TimedOut);
+    }
+    Ok(())
+}
+
+// Wakes up to `count` threads waiting on `addr`; returns how many there were
+pub fn wake(addr: usize, count: usize) -> usize {
+    let pid = PM.current_pid();
+    let Some(queue) = find(pid, addr) else {
+        return 0;
+    };
+
+    let mut woken = 0;
+    while woken < count && queue.wake_one() {
+        woken += 1;
+    }
+    release_if_empty(pid, addr);
+    woken
+}
@@ -0,0 +1,166 @@
+use core::{
+    arch::{asm, naked_asm},
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    constants::{MAX_HARTS, SCOUNTEREN_TM},
+    print, println,
+    process::{PM, idle},
+    sbi::sbi_call,
+    sync::lock_kernel,
+    timer::init_timer,
+    trap_handler::kernel_entry,
+    write_csr, write_csr_clear, write_csr_set,
+};
+
+const SBI_EID_HSM: usize = 0x48534d;
+const SBI_FID_HART_START: usize = 0;
+const SBI_EID_IPI: usize = 0x735049;
+const SBI_FID_SEND_IPI: usize = 0;
+const SBI_EID_RFENCE: usize = 0x52464e43;
+const SBI_FID_REMOTE_SFENCE_VMA: usize = 1;
+
+static ONLINE: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+// The hart the caller runs on. The kernel keeps it in tp, which the trap entry reloads since user
+// code is free to change it.
+pub fn id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) id, options(nomem, nostack));
+    }
+    id
+}
+
+pub fn is_online(hart: usize) -> bool {
+    ONLINE[hart].load(Ordering::Acquire)
+}
+
+pub fn online() -> usize {
+    (0..MAX_HARTS).filter(|&hart| is_online(hart)).count()
+}
+
+// The harts the threads of a process may run on, a bit per hart id. Children start with the
+// affinity of their parent.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Affinity(usize);
+
+impl Affinity {
+    pub const ALL: Self = Affinity((1 << MAX_HARTS) - 1);
+
+    // None unless the mask has a hart that is up
+    pub fn from_mask(mask: usize) -> Option<Self> {
+        let affinity = Affinity(mask & Affinity::ALL.0);
+        affinity.harts().next().map(|_| affinity)
+    }
+
+    pub fn contains(&self, hart: usize) -> bool {
+        self.0 & 1 << hart != 0
+    }
+
+    // The harts in the mask that are up
+    pub fn harts(self) -> impl Iterator<Item = usize> {
+        (0..MAX_HARTS).filter(move |&hart| self.contains(hart) && is_online(hart))
+    }
+}
+
+// What every hart sets up for itself before it takes part in scheduling
+pub fn init() {
+    unsafe {
+        write_csr!("stvec", kernel_entry);
+        write_csr!("scounteren", SCOUNTEREN_TM);
+    }
+
+    PM.init_hart(id());
+    unsafe {
+        write_csr_set!("sie", 1 << 1); // SSIE
+    }
+    init_timer();
+    ONLINE[id()].store(true, Ordering::Release);
+}
+
+// Asks SBI to start every other hart, each on the kernel stack of its idle thread. Hart ids the
+// machine does not have are refused, which is how we find out how many there are.
+pub fn start_secondaries() {
+    for hart in 0..MAX_HARTS {
+        if hart == id() {
+            continue;
+        }
+
+        let _ = sbi_call(
+            hart,
+            secondary_boot as usize,
+            PM.idle_stack_top(hart),
+            0,
+            0,
+            0,
+            SBI_FID_HART_START,
+            SBI_EID_HSM,
+        );
+    }
+}
+
+// Asks `hart` to run its scheduler, for when something was queued there. The request arrives as a
+// supervisor software interrupt.
+pub fn send_reschedule(hart: usize) {
+    if hart == id() || !is_online(hart) {
+        return;
+    }
+
+    let sbi_ret = sbi_call(1 << hart, 0, 0, 0, 0, 0, SBI_FID_SEND_IPI, SBI_EID_IPI);
+    if let Err(err) = sbi_ret {
+        panic!("Failed to send IPI to hart {hart}: {err}");
+    }
+}
+
+pub fn ack_reschedule() {
+    unsafe {
+        write_csr_clear!("sip", 1 << 1); // SSIP
+    }
+}
+
+// Flushes the TLB entries for `len` bytes from `vaddr` on the harts whose bits are set in
+// `harts`, and waits until they are done
+pub fn remote_sfence_vma(harts: usize, vaddr: usize, len: usize) {
+    if harts == 0 {
+        return;
+    }
+
+    let sbi_ret = sbi_call(
+        harts,
+        0,
+        vaddr,
+        len,
+        0,
+        0,
+        SBI_FID_REMOTE_SFENCE_VMA,
+        SBI_EID_RFENCE,
+    );
+    if let Err(err) = sbi_ret {
+        panic!("Failed to flush remote TLBs: {err}");
+    }
+}
+
+// SBI enters here with the hart id in a0, the stack top we passed in a1 and paging off
+#[unsafe(naked)]
+#[repr(align(4))]
+unsafe extern "C" fn secondary_boot() -> ! {
+    naked_asm!(
+        "
+        mv tp, a0
+        mv sp, a1
+        j {secondary_main}
+        ",
+        secondary_main = sym secondary_main,
+    )
+}
+
+extern "C" fn secondary_main() -> ! {
+    init();
+
+    lock_kernel();
+    println!("hart {} online", id());
+    idle();
+}
@@ -1,31 +1,11 @@
-use crate::constants::PROCS_MAX;
-use crate::process::Pid;
-use crate::process::{PM, State};
+pub use abi::Message;
 
-#[derive(Clone, Copy, Debug)]
-pub enum Message {
-    Ping,
-    Data {
-        a: usize,
-        b: usize,
-    },
-
-    DisplayPrint {
-        display: u8, // 0..3
-        line: u8,
-        text: [u8; 32],
-        len: u8,
-    },
-    DisplayClear(u8), // 0..3
-    DisplayDrawCell {
-        display: u8,
-        x: u8,
-        y: u8,
-        fg: u8,
-        bg: u8,
-        ch: char,
-    },
-}
+use alloc::collections::VecDeque;
+
+use crate::process::{PM, Pid, Process};
+use crate::sync::SpinLock;
+use crate::timer::get_time;
+use crate::wait_queue::WaitQueue;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Src {
@@ -38,24 +18,46 @@ pub enum IpcError {
     SelfSend,
     DeadlockDetected,
     SendQueueFull,
+    NoSuchProcess,
     UnexpectedState,
+    // another thread of the process is blocked in send or recv
+    Busy,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct SenderEntry {
-    src: Pid,
-    msg: Message,
+    pub src: Pid,
+    pub msg: Message,
+    // false for one restored from a checkpoint: its sender is blocked on the original receiver,
+    // not on this one
+    pub waiting: bool,
+}
+
+impl SenderEntry {
+    // Marks the send as delivered to a sender blocked on it and returns that sender to wake up
+    fn release(&self) -> Option<Pid> {
+        if !self.waiting {
+            return None;
+        }
+        if let Some(sender) = PM.get(self.src) {
+            sender.lock().ipc.pending_send = None;
+        }
+        Some(self.src)
+    }
 }
 
-#[derive(Clone, Copy, Debug)]
 pub struct Ipc {
     // receiver 用
     pub waiting_for: Option<Src>,
     // sender 用
     pub pending_send: Option<(Pid, Message)>,
-    // receiver 用
-    pub senders: [Option<SenderEntry>; PROCS_MAX],
+    // receiver 用: blocked senders in the order they sent, up to the `ipc_queue` limit
+    pub senders: VecDeque<SenderEntry>,
     pub inbox: Option<Message>,
+    // the thread blocked in send or recv; one at a time per process
+    pub waiters: WaitQueue,
+    // since when every send has found the destination's queue full, for the watchdog
+    pub full_since: Option<u64>,
 }
 
 impl Ipc {
@@ -63,8 +65,10 @@ impl Ipc {
         Ipc {
             waiting_for: None,
             pending_send: None,
-            senders: [None; PROCS_MAX],
+            senders: VecDeque::new(),
             inbox: None,
+            waiters: WaitQueue::new(),
+            full_since: None,
         }
     }
 
@@ -74,16 +78,19 @@ impl Ipc {
             return Err(IpcError::SelfSend);
         }
 
-        let me_idx = me.as_usize();
-        let dst_idx = dst.as_usize();
+        let dst_slot = PM.get(dst).ok_or(IpcError::NoSuchProcess)?;
+        let me_slot = PM.slot(me);
+        if !me_slot.lock().ipc.waiters.is_empty() {
+            return Err(IpcError::Busy);
+        }
 
         // deadlock detection
         {
-            let dst_proc = PM.procs[dst_idx].borrow();
+            let dst_proc = dst_slot.lock();
             if let Some((pending_dst, _)) = dst_proc.ipc.pending_send
                 && pending_dst == me
             {
-                let me_proc = PM.procs[me_idx].borrow();
+                let me_proc = me_slot.lock();
                 if let Some((my_dst, _)) = me_proc.ipc.pending_send
                     && my_dst == dst
                 {
@@ -94,8 +101,8 @@ impl Ipc {
 
         let mut should_unblock = false;
         {
-            let mut dst_proc = PM.procs[dst_idx].borrow_mut();
-            if dst_proc.state == State::Blocked
+            let mut dst_proc = dst_slot.lock();
+            if !dst_proc.ipc.waiters.is_empty()
                 && let Some(waiting) = dst_proc.ipc.waiting_for
             {
                 match waiting {
@@ -114,146 +121,190 @@ impl Ipc {
             }
         }
         if should_unblock {
+            me_slot.lock().ipc.full_since = None;
             PM.unblock(dst);
             return Ok(());
         }
 
         {
-            let mut dst_proc = PM.procs[dst_idx].borrow_mut();
-            for slot in dst_proc.ipc.senders.iter() {
-                if let Some(entry) = slot
-                    && entry.src == me
-                {
-                    return Err(IpcError::DeadlockDetected);
-                }
+            let mut dst_proc = dst_slot.lock();
+            if dst_proc
+                .ipc
+                .senders
+                .iter()
+                .any(|entry| entry.src == me && entry.waiting)
+            {
+                return Err(IpcError::DeadlockDetected);
             }
-            if let Some(entry_slot) = dst_proc.ipc.senders.iter_mut().find(|s| s.is_none()) {
-                *entry_slot = Some(SenderEntry { src: me, msg });
-            } else {
-                return Err(IpcError::SendQueueFull);
+            if dst_proc.ipc.senders.len() >= dst_proc.limits.ipc_queue {
+                return Err(queue_full(me_slot));
             }
+            dst_proc.ipc.senders.push_back(SenderEntry {
+                src: me,
+                msg,
+                waiting: true,
+            });
         }
 
         {
-            {
-                let mut me_proc = PM.procs[me_idx].borrow_mut();
-                if me_proc.ipc.pending_send.is_some() {
-                    return Err(IpcError::DeadlockDetected);
-                }
-                me_proc.ipc.pending_send = Some((dst, msg));
+            let mut me_proc = me_slot.lock();
+            if me_proc.ipc.pending_send.is_some() {
+                return Err(IpcError::DeadlockDetected);
             }
-            PM.block_current();
+            me_proc.ipc.pending_send = Some((dst, msg));
+            me_proc.ipc.waiters.prepare_wait();
         }
 
         PM.switch();
 
         {
-            let mut me_proc = PM.procs[me_idx].borrow_mut();
+            let mut me_proc = me_slot.lock();
             if me_proc.ipc.pending_send.is_some() {
                 me_proc.ipc.pending_send = None;
+                if PM.get(dst).is_none() {
+                    return Err(IpcError::NoSuchProcess);
+                }
                 return Err(IpcError::UnexpectedState);
             }
+            me_proc.ipc.full_since = None;
         }
 
         Ok(())
     }
     pub fn recv(src: Src) -> Result<Message, IpcError> {
         let me = PM.current_pid();
-        let me_idx = me.as_usize();
+        let me_slot = PM.slot(me);
+        if !me_slot.lock().ipc.waiters.is_empty() {
+            return Err(IpcError::Busy);
+        }
+
+        // handed to a recv that a checkpoint cut short, and made again after the restore
+        if let Some(msg) = me_slot.lock().ipc.inbox.take() {
+            return Ok(msg);
+        }
 
         if let Some((msg, sender)) = {
-            let mut me_proc = PM.procs[me_idx].borrow_mut();
-
-            if let Src::Specific(expected) = src {
-                if let Some(pos) = me_proc
-                    .ipc
-                    .senders
-                    .iter()
-                    .position(|slot| matches!(slot, Some(entry) if entry.src == expected))
-                {
-                    if let Some(entry) = me_proc.ipc.senders[pos].take() {
-                        {
-                            let mut sender_proc = PM.procs[entry.src.as_usize()].borrow_mut();
-                            sender_proc.ipc.pending_send = None;
-                        }
-                        me_proc.ipc.waiting_for = None;
-                        Some((entry.msg, entry.src))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            } else if let Some(pos) = me_proc.ipc.senders.iter().position(|s| s.is_some()) {
-                if let Some(entry) = me_proc.ipc.senders[pos].take() {
-                    {
-                        let mut sender_proc = PM.procs[entry.src.as_usize()].borrow_mut();
-                        sender_proc.ipc.pending_send = None;
-                    }
-                    me_proc.ipc.waiting_for = None;
-                    Some((entry.msg, entry.src))
-                } else {
-                    None
-                }
+            let mut me_proc = me_slot.lock();
+
+            if let Some(entry) = me_proc.ipc.take_sender(src) {
+                me_proc.ipc.waiting_for = None;
+                Some((entry.msg, entry.release()))
             } else if let Some(msg) = me_proc.ipc.inbox.take() {
                 return Ok(msg);
             } else {
                 None
             }
         } {
-            PM.unblock(sender);
+            if let Some(sender) = sender {
+                PM.unblock(sender);
+            }
             return Ok(msg);
         }
 
+        // `release` only wakes those that were already waiting when the process died
+        if let Src::Specific(src) = src
+            && PM.get(src).is_none()
+        {
+            return Err(IpcError::NoSuchProcess);
+        }
+
         {
-            let mut me_proc = PM.procs[me_idx].borrow_mut();
+            let mut me_proc = me_slot.lock();
             if me_proc.ipc.waiting_for.is_some() {
                 return Err(IpcError::DeadlockDetected);
             }
             me_proc.ipc.waiting_for = Some(src);
+            me_proc.ipc.waiters.prepare_wait();
         }
-        PM.block_current();
         PM.switch();
 
         {
-            let mut me_proc = PM.procs[me_idx].borrow_mut();
+            let mut me_proc = me_slot.lock();
 
             if let Some(msg) = me_proc.ipc.inbox.take() {
                 me_proc.ipc.waiting_for = None;
                 return Ok(msg);
             }
 
-            if let Some(Src::Specific(expected)) = me_proc.ipc.waiting_for
-                && let Some(pos) = me_proc
-                    .ipc
-                    .senders
-                    .iter()
-                    .position(|slot| matches!(slot, Some(entry) if entry.src == expected))
-                && let Some(entry) = me_proc.ipc.senders[pos].take()
+            if let Some(waiting) = me_proc.ipc.waiting_for
+                && let Some(entry) = me_proc.ipc.take_sender(waiting)
             {
-                {
-                    let mut sender_proc = PM.procs[entry.src.as_usize()].borrow_mut();
-                    sender_proc.ipc.pending_send = None;
-                }
                 me_proc.ipc.waiting_for = None;
-                PM.unblock(entry.src);
+                if let Some(sender) = entry.release() {
+                    PM.unblock(sender);
+                }
                 return Ok(entry.msg);
             }
 
-            if matches!(me_proc.ipc.waiting_for, Some(Src::Any))
-                && let Some(pos) = me_proc.ipc.senders.iter().position(|s| s.is_some())
-                && let Some(entry) = me_proc.ipc.senders[pos].take()
+            if let Some(Src::Specific(expected)) = me_proc.ipc.waiting_for.take()
+                && PM.get(expected).is_none()
             {
-                {
-                    let mut sender_proc = PM.procs[entry.src.as_usize()].borrow_mut();
-                    sender_proc.ipc.pending_send = None;
-                }
-                me_proc.ipc.waiting_for = None;
-                PM.unblock(entry.src);
-                return Ok(entry.msg);
+                return Err(IpcError::NoSuchProcess);
             }
         }
 
         Err(IpcError::UnexpectedState)
     }
+
+    // Takes the longest waiting sender `src` accepts off the queue
+    fn take_sender(&mut self, src: Src) -> Option<SenderEntry> {
+        let pos = self.senders.iter().position(|entry| match src {
+            Src::Specific(expected) => entry.src == expected,
+            Src::Any => true,
+        })?;
+        self.senders.remove(pos)
+    }
+}
+
+// Wakes up every process that is blocked on `dead` so that its send or recv fails with
+// `NoSuchProcess` instead of waiting forever, and takes back the sends of `dead` so that nobody
+// receives them after its death
+pub fn release(dead: Pid) {
+    withdraw(dead);
+
+    for slot in PM.slots() {
+        let pid = {
+            let proc = slot.lock();
+            if proc.ipc.waiters.is_empty() {
+                continue;
+            }
+
+            let sending_to_dead = matches!(proc.ipc.pending_send, Some((dst, _)) if dst == dead);
+            let waiting_for_dead = proc.ipc.waiting_for == Some(Src::Specific(dead));
+            if !sending_to_dead && !waiting_for_dead {
+                continue;
+            }
+
+            proc.pid
+        };
+
+        PM.unblock(pid);
+    }
+
+    let mut dead_proc = PM.slot(dead).lock();
+    dead_proc.ipc = Ipc::new();
+}
+
+// Withdraws the send or recv a thread of `pid` is blocked in, for when that thread is torn down
+// while the process lives on
+pub fn cancel(pid: Pid) {
+    withdraw(pid);
+
+    let mut proc = PM.slot(pid).lock();
+    proc.ipc.waiting_for = None;
+    proc.ipc.pending_send = None;
+    proc.ipc.waiters.clear();
+    proc.ipc.full_since = None;
+}
+
+// Takes the messages `pid` is blocked sending off the queues of their receivers
+fn withdraw(pid: Pid) {
+    for slot in PM.slots() {
+        slot.lock().ipc.senders.retain(|entry| entry.src != pid);
+    }
+}
+
+fn queue_full(me_slot: &SpinLock<Process>) -> IpcError {
+    me_slot.lock().ipc.full_since.get_or_insert(get_time());
+    IpcError::SendQueueFull
 }
@@ -2,39 +2,50 @@
 #![no_std]
 #![feature(fn_align, pointer_is_aligned_to)]
 
-#[macro_use]
 extern crate alloc;
 
-mod apps;
+mod checkpoint;
 mod constants;
+mod elf;
+mod futex;
+mod hart;
 mod ipc;
 mod memory;
 mod process;
+mod programs;
 mod sbi;
+mod sched;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod shm;
+mod sync;
+mod syscall;
 mod timer;
 mod trap_handler;
 mod utils;
+mod wait_queue;
+mod watchdog;
 
 use core::{arch::asm, fmt::Write, panic::PanicInfo, ptr};
 
 use crate::{
-    apps::{display, playground},
-    constants::{BSS, BSS_END, STACK_TOP},
+    constants::{bss, bss_end},
     memory::alloc_pages,
-    process::PM,
-    timer::init_timer,
-    trap_handler::kernel_entry,
+    process::{PM, Pid, idle},
+    sched::Priority,
+    sync::lock_kernel,
     utils::Addr,
 };
 
+// SBI starts a single hart here, with its id in a0; the others are started by `kernel_main`
 #[unsafe(no_mangle)]
 #[unsafe(link_section = ".text.boot")]
 extern "C" fn boot() -> ! {
     unsafe {
         asm!(
-            "mv sp, {stack_top}
+            "mv tp, a0
+            la sp, __stack_top
             j {kernel_main}",
-            stack_top = in(reg) STACK_TOP,
             kernel_main = sym kernel_main,
             options(noreturn)
         );
@@ -43,35 +54,43 @@ extern "C" fn boot() -> ! {
 
 fn kernel_main() -> ! {
     unsafe {
-        ptr::write_bytes(BSS, 0, BSS_END.offset_from(BSS) as usize);
-
-        write_csr!("stvec", kernel_entry);
+        ptr::write_bytes(bss(), 0, bss_end().offset_from(bss()) as usize);
     }
 
+    lock_kernel();
     PM.init();
-
-    init_timer();
+    hart::init();
 
     println!("Hello, World!");
 
-    let paddr0 = alloc_pages(2).as_usize();
-    let paddr1 = alloc_pages(1).as_usize();
+    let paddr0 = alloc_pages(2).expect("out of memory").as_usize();
+    let paddr1 = alloc_pages(1).expect("out of memory").as_usize();
 
     println!("alloc_pages test: paddr0 = {paddr0:x}");
     println!("alloc_pages test: paddr1 = {paddr1:x}");
 
-    PM.create_process(display::display_server as usize);
-
-    PM.create_process(playground::proc_a as usize);
-    PM.create_process(playground::proc_b as usize);
-    PM.create_process(playground::proc_c as usize);
-    PM.create_process(playground::proc_d as usize);
+    // init decides which programs run from here on, unless the self-tests take over
+    #[cfg(not(feature = "selftest"))]
+    start_user("init");
+    #[cfg(feature = "selftest")]
+    selftest::start(start_user("selftest"));
 
-    PM.switch();
-
-    unsafe { asm!("unimp") };
+    hart::start_secondaries();
+    idle();
+}
 
-    unreachable!();
+// Starts the first user program
+fn start_user(name: &str) -> Pid {
+    let program = programs::find(name).expect("no such program");
+    match PM.create_user_process(
+        program.name,
+        program.image,
+        &[program.name],
+        Priority::NORMAL,
+    ) {
+        Ok(pid) => pid,
+        Err(err) => panic!("failed to start {name}: {err:?}"),
+    }
 }
 
 #[panic_handler]
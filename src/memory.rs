@@ -1,33 +1,43 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::RefCell,
     ptr,
 };
 
 use crate::{
-    constants::{FREE_RAM, FREE_RAM_END, PAGE_SIZE, PAGE_V},
+    constants::{
+        KERNEL_RESERVE_PAGES, PAGE_R, PAGE_SIZE, PAGE_U, PAGE_V, PAGE_W, PAGE_X, free_ram,
+        free_ram_end, kernel_base,
+    },
+    sync::{SpinLock, SpinLockGuard},
     utils::{Addr, PhysAddr, VirtAddr},
 };
 
 struct Alocator {
-    head: RefCell<*mut u8>,
-    end: *const u8,
+    // null until the first allocation, as the free RAM is only known to the linker
+    head: SpinLock<*mut u8>,
 }
 
 impl Alocator {
-    const fn new(start: *mut u8, end: *const u8) -> Self {
+    const fn new() -> Self {
         Alocator {
-            head: RefCell::new(start),
-            end,
+            head: SpinLock::new(ptr::null_mut()),
         }
     }
+
+    fn head(&self) -> SpinLockGuard<'_, *mut u8> {
+        let mut head = self.head.lock();
+        if head.is_null() {
+            *head = free_ram();
+        }
+        head
+    }
 }
 
 unsafe impl Sync for Alocator {}
 
 unsafe impl GlobalAlloc for Alocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let head = *self.head.borrow();
+        let mut head = self.head();
 
         let size = layout.size();
         let align = layout.align();
@@ -36,12 +46,11 @@ unsafe impl GlobalAlloc for Alocator {
         let alloc_start = unsafe { head.add(padding) };
         let alloc_end = unsafe { alloc_start.add(size) };
 
-        if alloc_end as *const u8 > self.end {
+        if alloc_end as *const u8 > free_ram_end() {
             ptr::null_mut()
         } else {
             unsafe { ptr::write_bytes(alloc_start, 0, size) };
 
-            let mut head = self.head.borrow_mut();
             *head = alloc_end;
 
             alloc_start
@@ -52,27 +61,65 @@ unsafe impl GlobalAlloc for Alocator {
 }
 
 #[global_allocator]
-static HEAP: Alocator = unsafe { Alocator::new(FREE_RAM, FREE_RAM_END) };
+static HEAP: Alocator = Alocator::new();
+
+// Single pages handed back with `free_page`, linked through their first word
+struct FreeList {
+    head: usize,
+    len: usize,
+}
 
-pub fn alloc_pages(num: usize) -> PhysAddr {
-    let size = num * PAGE_SIZE;
+static FREE_LIST: SpinLock<FreeList> = SpinLock::new(FreeList { head: 0, len: 0 });
 
-    let layout = match Layout::from_size_align(size, PAGE_SIZE) {
-        Ok(layout) => layout,
-        Err(_) => {
-            panic!("Invalid layout for allocation");
+// Zeroed pages, or None once RAM is used up. Single pages come from the free list first.
+pub fn alloc_pages(num: usize) -> Option<PhysAddr> {
+    if num == 1 {
+        let mut list = FREE_LIST.lock();
+        if list.head != 0 {
+            let page = list.head as *mut u8;
+            list.head = unsafe { *(page as *const usize) };
+            list.len -= 1;
+            unsafe { ptr::write_bytes(page, 0, PAGE_SIZE) };
+            return Some(PhysAddr::from_ptr(page));
         }
-    };
+    }
 
+    let layout = Layout::from_size_align(num * PAGE_SIZE, PAGE_SIZE).ok()?;
     let ptr = unsafe { HEAP.alloc(layout) };
     if ptr.is_null() {
-        panic!("Out of memory");
+        return None;
+    }
+
+    Some(PhysAddr::from_ptr(ptr))
+}
+
+// A page for an address space: user memory or a page table. These fail while the kernel could
+// still allocate, so that running out of memory is an error for the process asking and not a
+// panic somewhere in the kernel.
+pub fn alloc_user_page() -> Option<PhysAddr> {
+    if free_pages() <= KERNEL_RESERVE_PAGES {
+        return None;
     }
+    alloc_pages(1)
+}
+
+// The caller makes sure nobody can reach the page any more, flushing TLBs if needed
+pub fn free_page(paddr: PhysAddr) {
+    let mut list = FREE_LIST.lock();
+    unsafe { *(paddr.as_ptr_mut() as *mut usize) = list.head };
+    list.head = paddr.as_usize();
+    list.len += 1;
+}
 
-    PhysAddr::from_ptr(ptr)
+// Pages left for allocation, whoever asks
+pub fn free_pages() -> usize {
+    let head = *HEAP.head() as usize;
+    let left = (free_ram_end() as usize).saturating_sub(head) / PAGE_SIZE;
+    left + FREE_LIST.lock().len
 }
 
-pub fn map_page(page_table: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags: u32) {
+// Fails if a page table page was needed but none was left
+pub fn map_page(page_table: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags: u32) -> bool {
     if !vaddr.is_aligned(PAGE_SIZE) || !paddr.is_aligned(PAGE_SIZE) {
         panic!("Virtual and physical addresses must be page-aligned");
     }
@@ -81,7 +128,9 @@ pub fn map_page(page_table: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags: u
     let vpn1 = ((vaddr.as_usize() >> 22) & 0x3ff) as isize;
 
     if unsafe { *table1.offset(vpn1) } & PAGE_V == 0 {
-        let pt_paddr = alloc_pages(1);
+        let Some(pt_paddr) = alloc_user_page() else {
+            return false;
+        };
         unsafe { *table1.offset(vpn1) = ((pt_paddr.as_usize() / PAGE_SIZE) << 10) as u32 | PAGE_V };
     }
 
@@ -91,4 +140,182 @@ pub fn map_page(page_table: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags: u
     unsafe {
         *(table0.offset(vpn0)) = ((paddr.as_usize() / PAGE_SIZE) << 10) as u32 | flags | PAGE_V
     };
+    true
+}
+
+// Maps a fresh page for user space at `vaddr`
+pub fn map_user_page(page_table: PhysAddr, vaddr: VirtAddr, flags: u32) -> bool {
+    let Some(paddr) = alloc_user_page() else {
+        return false;
+    };
+    if !map_page(page_table, vaddr, paddr, flags) {
+        free_page(paddr);
+        return false;
+    }
+    true
+}
+
+// Clears the mapping of the page at `vaddr` and returns the page it was mapped to. The caller
+// flushes the TLBs that may still hold it.
+pub fn unmap_page(page_table: PhysAddr, vaddr: VirtAddr) -> Option<PhysAddr> {
+    let pte = pte_slot(page_table, vaddr)?;
+    let old = unsafe { *pte };
+    unsafe { *pte = 0 };
+    Some(pte_to_paddr(old, vaddr))
+}
+
+fn pte_slot(page_table: PhysAddr, vaddr: VirtAddr) -> Option<*mut u32> {
+    let table1 = page_table.as_usize() as *const u32;
+    let vpn1 = ((vaddr.as_usize() >> 22) & 0x3ff) as isize;
+
+    let pte1 = unsafe { *table1.offset(vpn1) };
+    if pte1 & PAGE_V == 0 {
+        return None;
+    }
+
+    let table0 = ((pte1 >> 10) * PAGE_SIZE as u32) as *mut u32;
+    let vpn0 = ((vaddr.as_usize() >> 12) & 0x3ff) as isize;
+
+    let pte0 = unsafe { table0.offset(vpn0) };
+    if unsafe { *pte0 } & PAGE_V == 0 {
+        return None;
+    }
+
+    Some(pte0)
+}
+
+fn lookup(page_table: PhysAddr, vaddr: VirtAddr) -> Option<u32> {
+    pte_slot(page_table, vaddr).map(|pte| unsafe { *pte })
+}
+
+fn pte_to_paddr(pte: u32, vaddr: VirtAddr) -> PhysAddr {
+    PhysAddr::from_usize((pte as usize >> 10) * PAGE_SIZE + vaddr.as_usize() % PAGE_SIZE)
+}
+
+pub fn translate(page_table: PhysAddr, vaddr: VirtAddr) -> Option<PhysAddr> {
+    lookup(page_table, vaddr).map(|pte| pte_to_paddr(pte, vaddr))
+}
+
+// Like `translate`, but only for pages mapped with PAGE_U, so that pointers handed in by user
+// processes can't reach kernel memory
+fn translate_user(page_table: PhysAddr, vaddr: VirtAddr) -> Option<PhysAddr> {
+    let pte = lookup(page_table, vaddr)?;
+    if pte & PAGE_U == 0 {
+        return None;
+    }
+    Some(pte_to_paddr(pte, vaddr))
+}
+
+// Calls `f` with the address and PTE of every page mapped for user space, i.e. with PAGE_U
+pub fn for_each_user_page(page_table: PhysAddr, mut f: impl FnMut(VirtAddr, PhysAddr, u32)) {
+    let table1 = page_table.as_usize() as *const u32;
+    for vpn1 in 0..1024 {
+        let pte1 = unsafe { *table1.add(vpn1) };
+        if pte1 & PAGE_V == 0 {
+            continue;
+        }
+
+        let table0 = ((pte1 >> 10) * PAGE_SIZE as u32) as *const u32;
+        for vpn0 in 0..1024 {
+            let pte0 = unsafe { *table0.add(vpn0) };
+            if pte0 & PAGE_V != 0 && pte0 & PAGE_U != 0 {
+                let vaddr = VirtAddr::from_usize(vpn1 << 22 | vpn0 << 12);
+                f(vaddr, pte_to_paddr(pte0, vaddr), pte0);
+            }
+        }
+    }
+}
+
+// Number of pages mapped for user space
+pub fn count_user_pages(page_table: PhysAddr) -> usize {
+    let mut count = 0;
+    for_each_user_page(page_table, |_, _, _| count += 1);
+    count
+}
+
+// The copies go through the physical mapping, so `page_table` doesn't have to be the active one
+pub fn copy_to_user(page_table: PhysAddr, vaddr: VirtAddr, data: &[u8]) -> bool {
+    let mut copied = 0;
+    while copied < data.len() {
+        let dst = vaddr.as_usize() + copied;
+        let Some(paddr) = translate_user(page_table, VirtAddr::from_usize(dst)) else {
+            return false;
+        };
+
+        let len = (PAGE_SIZE - dst % PAGE_SIZE).min(data.len() - copied);
+        unsafe {
+            ptr::copy_nonoverlapping(data[copied..].as_ptr(), paddr.as_ptr_mut(), len);
+        }
+        copied += len;
+    }
+    true
+}
+
+// Whether all of the `len` bytes at `vaddr` are mapped for user space, so that reading them
+// later cannot fail
+pub fn is_user_range(page_table: PhysAddr, vaddr: VirtAddr, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let Some(last) = vaddr.as_usize().checked_add(len - 1) else {
+        return false;
+    };
+    let first = vaddr.as_usize() - vaddr.as_usize() % PAGE_SIZE;
+    (first..=last)
+        .step_by(PAGE_SIZE)
+        .all(|page| translate_user(page_table, VirtAddr::from_usize(page)).is_some())
+}
+
+pub fn copy_from_user(page_table: PhysAddr, vaddr: VirtAddr, buf: &mut [u8]) -> bool {
+    let mut copied = 0;
+    while copied < buf.len() {
+        let src = vaddr.as_usize() + copied;
+        let Some(paddr) = translate_user(page_table, VirtAddr::from_usize(src)) else {
+            return false;
+        };
+
+        let len = (PAGE_SIZE - src % PAGE_SIZE).min(buf.len() - copied);
+        unsafe {
+            ptr::copy_nonoverlapping(paddr.as_ptr(), buf[copied..].as_mut_ptr(), len);
+        }
+        copied += len;
+    }
+    true
+}
+
+// A fresh page table with the kernel identity-mapped. PAGE_U is left out so that user processes
+// sharing the table cannot touch kernel memory.
+pub fn new_page_table() -> Option<PhysAddr> {
+    let page_table = alloc_user_page()?;
+
+    let mut paddr = kernel_base();
+    while paddr < free_ram_end() {
+        let mapped = map_page(
+            page_table,
+            VirtAddr::from_ptr(paddr),
+            PhysAddr::from_ptr(paddr),
+            PAGE_R | PAGE_W | PAGE_X,
+        );
+        if !mapped {
+            free_page_table(page_table);
+            return None;
+        }
+        paddr = unsafe { paddr.add(PAGE_SIZE) };
+    }
+
+    Some(page_table)
+}
+
+// Frees a page table that no hart has loaded, along with the user pages mapped in it
+pub fn free_page_table(page_table: PhysAddr) {
+    for_each_user_page(page_table, |_, paddr, _| free_page(paddr));
+
+    let table1 = page_table.as_usize() as *const u32;
+    for vpn1 in 0..1024 {
+        let pte1 = unsafe { *table1.add(vpn1) };
+        if pte1 & PAGE_V != 0 {
+            free_page(PhysAddr::from_usize((pte1 as usize >> 10) * PAGE_SIZE));
+        }
+    }
+    free_page(page_table);
 }
@@ -1,23 +1,112 @@
+mod pid;
+
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::{
     arch::{asm, naked_asm},
-    cell::RefCell,
+    array,
+    cmp::Reverse,
+    convert::Infallible,
+    fmt, mem, ptr,
 };
 
+use abi::PID_INDEX_BITS;
+
 use crate::{
+    checkpoint::{Checkpoint, CheckpointError},
     constants::{
-        FREE_RAM_END, KERNEL_BASE, KERNEL_STACK_SIZE, PAGE_R, PAGE_SIZE, PAGE_W, PAGE_X, PROCS_MAX,
-        SATP_SV32,
+        KERNEL_STACK_SIZE, LOADAVG_PERIOD_US, MAX_HARTS, PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W,
+        PROC_NAME_MAX, RLIMIT_CHILDREN, RLIMIT_IPC_QUEUE, RLIMIT_PAGES, RLIMIT_SHM, SATP_SV32,
+        SCHED_BALANCE_PERIOD_US, SSTATUS_SIE, SSTATUS_SPIE, USER_HEAP_BASE, USER_SHM_BASE,
+        USER_STACK_PAGES, USER_STACK_TOP,
+    },
+    elf::{self, ElfError},
+    futex,
+    hart::{self, Affinity},
+    ipc::{self, Ipc, Src},
+    memory::{
+        alloc_pages, copy_to_user, count_user_pages, free_page_table, map_user_page, new_page_table,
     },
-    ipc::Ipc,
-    memory::{alloc_pages, map_page},
-    utils::{Addr, PhysAddr, VirtAddr},
+    sched::{self, Priority, SchedClass, SchedEntity, SchedError, Scheduler},
+    shm,
+    sync::{SpinLock, lock_kernel, unlock_kernel},
+    timer::{
+        Instant, cancel_timers, get_time, has_expired, pop_expired, set_preemption,
+        take_watchdog_due, us_to_ticks, wake_at,
+    },
+    trap_handler::{TRAP_FRAME_SPACE, TrapFrame, trap_return},
+    utils::{Addr, PhysAddr, VirtAddr, irq_disable, irq_enable},
+    wait_queue::WaitQueue,
+    watchdog::{self, Action},
+    write_csr,
 };
 
-#[derive(Clone, Copy, PartialEq)]
+use pid::next_generation;
+pub use pid::{Pid, Tid};
+
+// Why a process or thread could not be created
+#[derive(Debug)]
+pub enum CreateError {
+    // no free slot in the process or thread table
+    TableFull,
+    // no memory left for the kernel stack of a new thread
+    OutOfMemory,
+}
+
+impl From<CreateError> for ElfError {
+    fn from(err: CreateError) -> Self {
+        match err {
+            CreateError::TableFull => ElfError::TableFull,
+            CreateError::OutOfMemory => ElfError::OutOfMemory,
+        }
+    }
+}
+
+impl From<CreateError> for SchedError {
+    fn from(err: CreateError) -> Self {
+        match err {
+            CreateError::TableFull => SchedError::TableFull,
+            CreateError::OutOfMemory => SchedError::OutOfMemory,
+        }
+    }
+}
+
+impl From<CreateError> for CheckpointError {
+    fn from(err: CreateError) -> Self {
+        match err {
+            CreateError::TableFull => CheckpointError::TableFull,
+            CreateError::OutOfMemory => CheckpointError::OutOfMemory,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum State {
     Unused,
     Blocked,
+    Sleeping,
     Runnable,
+    // a real-time thread that used up its budget, waiting for its next period
+    Throttled,
+    // a thread that is done, waiting to be joined
+    Exited,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            State::Unused => "unused",
+            State::Blocked => "blocked",
+            State::Sleeping => "sleeping",
+            State::Runnable => "runnable",
+            State::Throttled => "throttled",
+            State::Exited => "exited",
+        })
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -60,164 +149,898 @@ impl Context {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub struct Pid(usize);
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Name {
+    buf: [u8; PROC_NAME_MAX],
+    len: usize,
+}
 
-impl Pid {
-    pub const fn new(pid: usize) -> Self {
-        Pid(pid)
+impl Name {
+    const fn empty() -> Self {
+        Name {
+            buf: [0; PROC_NAME_MAX],
+            len: 0,
+        }
     }
 
-    pub const fn idle() -> Self {
-        Pid(0)
+    pub fn new(name: &str) -> Self {
+        let mut len = name.len().min(PROC_NAME_MAX);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut buf = [0; PROC_NAME_MAX];
+        buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Name { buf, len }
     }
 
-    pub fn is_idle(&self) -> bool {
-        self == &Pid::idle()
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("?")
     }
+}
 
-    pub fn as_usize(&self) -> usize {
-        self.0
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
     }
 }
 
 #[derive(Clone, Copy)]
+pub struct CpuStats {
+    pub user_time: u64,
+    pub system_time: u64,
+    // switched out while blocked or sleeping
+    pub voluntary_switches: u64,
+    // switched out while still runnable
+    pub involuntary_switches: u64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        CpuStats {
+            user_time: 0,
+            system_time: 0,
+            voluntary_switches: 0,
+            involuntary_switches: 0,
+        }
+    }
+
+    pub fn cpu_time(&self) -> u64 {
+        self.user_time + self.system_time
+    }
+
+    fn add(&mut self, other: &CpuStats) {
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resource {
+    Pages,
+    CpuTime,
+    Children,
+    IpcQueue,
+    SharedMemory,
+}
+
+// Caps on what a process may use; reaching one makes the request fail instead of draining what
+// everybody shares. Children start with the limits of their parent.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    // user pages mapped for the image, the stack and the heap
+    pub pages: usize,
+    // ticks of CPU time over all threads; the process is killed once it runs past this
+    pub cpu_time: u64,
+    pub children: usize,
+    // messages that may wait in its IPC queue
+    pub ipc_queue: usize,
+    // pages of shared memory attached at a time
+    pub shm: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Limits = Limits {
+        pages: RLIMIT_PAGES,
+        cpu_time: u64::MAX,
+        children: RLIMIT_CHILDREN,
+        ipc_queue: RLIMIT_IPC_QUEUE,
+        shm: RLIMIT_SHM,
+    };
+
+    pub fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Pages => self.pages as u64,
+            Resource::CpuTime => self.cpu_time,
+            Resource::Children => self.children as u64,
+            Resource::IpcQueue => self.ipc_queue as u64,
+            Resource::SharedMemory => self.shm as u64,
+        }
+    }
+
+    pub fn set(&mut self, resource: Resource, value: u64) {
+        let count = value.min(usize::MAX as u64) as usize;
+        match resource {
+            Resource::Pages => self.pages = count,
+            Resource::CpuTime => self.cpu_time = value,
+            Resource::Children => self.children = count,
+            Resource::IpcQueue => self.ipc_queue = count,
+            Resource::SharedMemory => self.shm = count,
+        }
+    }
+}
+
+// What the threads of a process share: the address space, the IPC identity and resources
 pub struct Process {
     pub pid: Pid,
-    pub state: State,
+    pub parent: Pid,
+    pub name: Name,
+    pub alive: bool,
+    pub entry: usize,
+    // program and arguments the process is running, so that the watchdog can start it over
+    image: &'static [u8],
+    argv: Vec<String>,
+    pub watchdog: Action,
+    pub affinity: Affinity,
+    pub start_time: u64,
+    // CPU time of the threads that are gone
+    pub stats: CpuStats,
+    pub limits: Limits,
+    // usage counted against `limits`
+    pub pages: usize,
+    pub cpu_time: u64,
     page_table: PhysAddr,
-    context: Context,
-    sscratch: [usize; 2],
-    stack: [usize; KERNEL_STACK_SIZE],
+    // program break of user processes and the end of the pages mapped for the heap so far
+    brk: usize,
+    heap_mapped: usize,
     pub ipc: Ipc,
 }
 
 impl Process {
     const fn new() -> Self {
         Process {
-            pid: Pid(0),
-            state: State::Unused,
+            pid: Pid::idle(),
+            parent: Pid::idle(),
+            name: Name::empty(),
+            alive: false,
+            entry: 0,
+            image: &[],
+            argv: Vec::new(),
+            watchdog: Action::Report,
+            affinity: Affinity::ALL,
+            start_time: 0,
+            stats: CpuStats::new(),
+            limits: Limits::DEFAULT,
+            pages: 0,
+            cpu_time: 0,
             page_table: PhysAddr::NULL,
-            context: Context::new(),
-            sscratch: [0; 2],
-            stack: [0; KERNEL_STACK_SIZE],
+            brk: USER_HEAP_BASE,
+            heap_mapped: USER_HEAP_BASE,
             ipc: Ipc::new(),
         }
     }
 }
 
-struct RunQueue {
-    queue: RefCell<[Pid; PROCS_MAX]>,
-    head: RefCell<usize>,
-    tail: RefCell<usize>,
+// Allocated apart from its thread, so that thread slots stay small. It is never freed but goes
+// along with the slot to the next thread that uses it.
+struct KernelStack(PhysAddr);
+
+impl KernelStack {
+    fn new() -> Option<Self> {
+        alloc_pages(KERNEL_STACK_SIZE / PAGE_SIZE).map(KernelStack)
+    }
+
+    fn top(&self) -> usize {
+        self.0.as_usize() + KERNEL_STACK_SIZE
+    }
+}
+
+// What the scheduler runs: a context and kernel stack inside a process
+pub struct Thread {
+    pub tid: Tid,
+    pub pid: Pid,
+    pub state: State,
+    pub stats: CpuStats,
+    // when the running time was last charged to `stats`
+    accounted_at: u64,
+    pub sched: SchedEntity,
+    // the hart whose run queue the thread is on, or that it last ran on, and a copy of the
+    // affinity of its process
+    hart: usize,
+    affinity: Affinity,
+    dispatched_at: u64,
+    // when the thread last ran or changed state, for the watchdog
+    pub since: u64,
+    // what an exited thread hands to join, and the thread waiting for it there
+    exit_code: usize,
+    joiner: WaitQueue,
+    // torn down while running on another hart; it exits on its next way into the kernel
+    killed: bool,
+    context: Context,
+    // what the trap entry finds through sscratch: a spot to save a0, the top of the kernel stack
+    // and the hart the thread runs on
+    sscratch: [usize; 3],
+    stack: KernelStack,
 }
 
-impl RunQueue {
-    const fn new() -> Self {
-        RunQueue {
-            queue: RefCell::new([Pid(0); PROCS_MAX]),
-            head: RefCell::new(0),
-            tail: RefCell::new(0),
+impl Thread {
+    // None if there is no memory left for its kernel stack
+    fn new() -> Option<Self> {
+        Some(Thread {
+            tid: Tid::idle(0),
+            pid: Pid::idle(),
+            state: State::Unused,
+            stats: CpuStats::new(),
+            accounted_at: 0,
+            sched: SchedEntity::new(Priority::NORMAL),
+            hart: 0,
+            affinity: Affinity::ALL,
+            dispatched_at: 0,
+            since: 0,
+            exit_code: 0,
+            joiner: WaitQueue::new(),
+            killed: false,
+            context: Context::new(),
+            sscratch: [0; 3],
+            stack: KernelStack::new()?,
+        })
+    }
+
+    fn is_live(&self) -> bool {
+        !self.killed && !matches!(self.state, State::Unused | State::Exited)
+    }
+
+    fn stack_top(&self) -> usize {
+        self.stack.top()
+    }
+
+    fn frame(&self) -> *mut TrapFrame {
+        (self.stack_top() - TRAP_FRAME_SPACE) as *mut TrapFrame
+    }
+
+    // The user registers of a thread that is not running: the ones it is about to start with, or
+    // those its last trap from U-mode saved
+    fn user_frame(&self) -> TrapFrame {
+        if self.context.ra == user_entry as usize {
+            return TrapFrame {
+                sepc: self.context.s0,
+                sp: self.context.s1,
+                a0: self.context.s2,
+                ..TrapFrame::default()
+            };
         }
+        unsafe { ptr::read(self.frame()) }
     }
 
-    fn enqueue(&self, pid: Pid) {
-        if pid.is_idle() {
-            return;
+    fn account(&mut self, now: u64, user: bool) -> u64 {
+        let delta = now - self.accounted_at;
+        if user {
+            self.stats.user_time += delta;
+        } else {
+            self.stats.system_time += delta;
         }
+        self.accounted_at = now;
+        delta
+    }
+}
 
-        let tail = *self.tail.borrow();
-        self.queue.borrow_mut()[tail] = pid;
-        *self.tail.borrow_mut() = (tail + 1) % PROCS_MAX;
+#[derive(Debug)]
+pub enum ThreadError {
+    NoSuchThread,
+    // joining itself
+    Deadlock,
+    AlreadyJoined,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WaitReason {
+    Send(Pid),
+    Recv(Src),
+}
+
+impl WaitReason {
+    pub fn of(proc: &Process) -> Option<Self> {
+        if let Some((dst, _)) = proc.ipc.pending_send {
+            Some(WaitReason::Send(dst))
+        } else {
+            proc.ipc.waiting_for.map(WaitReason::Recv)
+        }
+    }
+}
+
+impl fmt::Display for WaitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitReason::Send(dst) => write!(f, "send -> {dst}"),
+            WaitReason::Recv(Src::Specific(src)) => write!(f, "recv <- {src}"),
+            WaitReason::Recv(Src::Any) => write!(f, "recv <- any"),
+        }
     }
+}
 
-    fn dequeue(&self) -> Option<Pid> {
-        if *self.head.borrow() == *self.tail.borrow() {
-            None
+#[derive(Clone, Copy)]
+pub struct ProcInfo {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: Name,
+    pub state: State,
+    pub priority: Priority,
+    pub class: SchedClass,
+    pub entry: usize,
+    pub start_time: u64,
+    pub stats: CpuStats,
+    pub threads: usize,
+    pub wait: Option<WaitReason>,
+}
+
+impl ProcInfo {
+    // `threads` are the live threads of `proc`, the first one standing in for the process when
+    // it comes to scheduling; the process counts as runnable as soon as one of them is
+    fn from_process(proc: &Process, threads: &[&Thread]) -> Self {
+        let mut stats = proc.stats;
+        for thread in threads {
+            stats.add(&thread.stats);
+        }
+        let state = if threads.iter().any(|thread| thread.state == State::Runnable) {
+            State::Runnable
         } else {
-            let head = *self.head.borrow();
-            let pid = self.queue.borrow()[head];
-            *self.head.borrow_mut() = (head + 1) % PROCS_MAX;
-            Some(pid)
+            threads.first().map_or(State::Exited, |thread| thread.state)
+        };
+        let sched = threads
+            .first()
+            .map_or(SchedEntity::new(Priority::NORMAL), |thread| thread.sched);
+
+        ProcInfo {
+            pid: proc.pid,
+            parent: proc.parent,
+            name: proc.name,
+            state,
+            priority: sched.priority,
+            class: sched.class,
+            entry: proc.entry,
+            start_time: proc.start_time,
+            stats,
+            threads: threads.len(),
+            wait: WaitReason::of(proc),
+        }
+    }
+}
+
+impl ProcInfo {
+    // Column titles lined up with the Display output
+    pub fn header() -> String {
+        format!(
+            "{:<8} {:<8} {:<16} {:<8} {:>3} {:>4} {:<9} {:>10} {:>10} {:>10} {:>10} {:>6} {:>6} WAIT",
+            "PID",
+            "PPID",
+            "NAME",
+            "STATE",
+            "THR",
+            "PRIO",
+            "CLASS",
+            "ENTRY",
+            "START",
+            "USER",
+            "SYS",
+            "VCSW",
+            "ICSW",
+        )
+    }
+}
+
+impl fmt::Display for ProcInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>4}.{:<3} {:>4}.{:<3} {:<16} {:<8} {:>3} {:>4} {:<9} {:>#10x} {:>8}ms {:>8}ms {:>8}ms {:>6} {:>6}",
+            self.pid.index,
+            self.pid.generation,
+            self.parent.index,
+            self.parent.generation,
+            self.name,
+            self.state,
+            self.threads,
+            self.priority.as_usize(),
+            self.class,
+            self.entry,
+            ticks_to_ms(self.start_time),
+            ticks_to_ms(self.stats.user_time),
+            ticks_to_ms(self.stats.system_time),
+            self.stats.voluntary_switches,
+            self.stats.involuntary_switches,
+        )?;
+        if let Some(wait) = self.wait {
+            write!(f, " {wait}")?;
+        }
+        Ok(())
+    }
+}
+
+fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks / us_to_ticks(1_000)
+}
+
+const FSHIFT: u32 = 11;
+const FIXED_1: u64 = 1 << FSHIFT;
+// FIXED_1 / exp(5s / 1min), FIXED_1 / exp(5s / 5min), FIXED_1 / exp(5s / 15min)
+const LOADAVG_EXP: [u64; 3] = [1884, 2014, 2037];
+
+// Exponentially decaying averages of the number of runnable processes over 1, 5 and 15 minutes,
+// in FSHIFT-bit fixed point
+#[derive(Clone, Copy)]
+pub struct LoadAvg([u64; 3]);
+
+impl LoadAvg {
+    const fn new() -> Self {
+        LoadAvg([0; 3])
+    }
+
+    fn update(&mut self, runnable: u64) {
+        for (load, exp) in self.0.iter_mut().zip(LOADAVG_EXP) {
+            *load = (*load * exp + runnable * FIXED_1 * (FIXED_1 - exp)) >> FSHIFT;
+        }
+    }
+}
+
+impl fmt::Display for LoadAvg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, load) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            let frac = (load & (FIXED_1 - 1)) * 100 / FIXED_1;
+            write!(f, "{}.{:02}", load >> FSHIFT, frac)?;
         }
+        Ok(())
     }
 }
 
 pub struct ProcessManager {
-    pub procs: [RefCell<Process>; PROCS_MAX],
-    pub current: RefCell<Pid>,
-    run_queue: RunQueue,
+    // slots are leaked so that references to them stay valid while the tables grow
+    procs: SpinLock<Vec<&'static SpinLock<Process>>>,
+    threads: SpinLock<Vec<&'static SpinLock<Thread>>>,
+    // the thread running on each hart
+    current: [SpinLock<Tid>; MAX_HARTS],
+    // a run queue per hart
+    schedulers: [&'static dyn Scheduler; MAX_HARTS],
+    load: SpinLock<LoadAvg>,
+    next_load_sample: SpinLock<u64>,
+    next_balance: SpinLock<u64>,
+    // the page table loaded on each hart
+    loaded: [SpinLock<PhysAddr>; MAX_HARTS],
+    // page tables of dead processes, freed once no hart has them loaded any more
+    retired: SpinLock<Vec<PhysAddr>>,
 }
 
 impl ProcessManager {
-    pub const fn new() -> Self {
+    pub const fn new(schedulers: [&'static dyn Scheduler; MAX_HARTS]) -> Self {
         ProcessManager {
-            procs: [const { RefCell::new(Process::new()) }; PROCS_MAX],
-            current: RefCell::new(Pid::idle()),
-            run_queue: RunQueue::new(),
+            procs: SpinLock::new(Vec::new()),
+            threads: SpinLock::new(Vec::new()),
+            current: [const { SpinLock::new(Tid::idle(0)) }; MAX_HARTS],
+            schedulers,
+            load: SpinLock::new(LoadAvg::new()),
+            next_load_sample: SpinLock::new(0),
+            next_balance: SpinLock::new(0),
+            loaded: [const { SpinLock::new(PhysAddr::NULL) }; MAX_HARTS],
+            retired: SpinLock::new(Vec::new()),
         }
     }
 
+    pub fn current_tid(&self) -> Tid {
+        *self.current[hart::id()].lock()
+    }
+
+    // The other hart `tid` is running on, if any
+    fn running_elsewhere(&self, tid: Tid) -> Option<usize> {
+        let me = hart::id();
+        (0..MAX_HARTS).find(|&hart| hart != me && *self.current[hart].lock() == tid)
+    }
+
     pub fn current_pid(&self) -> Pid {
-        *self.current.borrow()
+        self.thread(self.current_tid()).lock().pid
     }
 
-    pub fn init(&self) {
-        let idle_pid = Pid::idle();
+    // Looks up a slot by index only; callers must know that `pid` is alive
+    pub fn slot(&self, pid: Pid) -> &'static SpinLock<Process> {
+        self.procs.lock()[pid.index]
+    }
 
-        let mut idle_proc = Process::new();
+    pub fn get(&self, pid: Pid) -> Option<&'static SpinLock<Process>> {
+        let slot = *self.procs.lock().get(pid.index)?;
+        let proc = slot.lock();
+        if !proc.alive || proc.pid != pid {
+            return None;
+        }
+        Some(slot)
+    }
 
-        let page_table = alloc_pages(1);
+    // Every slot of the process table, which only ever grows. The table is locked just long
+    // enough to fetch each one, so the caller may lock slots and call back into the manager.
+    pub fn slots(&self) -> impl Iterator<Item = &'static SpinLock<Process>> + '_ {
+        (0..).map_while(|index| self.procs.lock().get(index).copied())
+    }
 
-        let mut paddr = unsafe { KERNEL_BASE };
-        while paddr < unsafe { FREE_RAM_END } {
-            map_page(
-                page_table,
-                VirtAddr::from_ptr(paddr),
-                PhysAddr::from_ptr(paddr),
-                PAGE_R | PAGE_W | PAGE_X,
-            );
-            paddr = unsafe { paddr.add(PAGE_SIZE) };
+    // Same as `slot` for the thread table
+    pub fn thread(&self, tid: Tid) -> &'static SpinLock<Thread> {
+        self.threads.lock()[tid.index]
+    }
+
+    pub fn get_thread(&self, tid: Tid) -> Option<&'static SpinLock<Thread>> {
+        let slot = *self.threads.lock().get(tid.index)?;
+        let thread = slot.lock();
+        if thread.state == State::Unused || thread.tid != tid {
+            return None;
+        }
+        Some(slot)
+    }
+
+    pub fn threads(&self) -> impl Iterator<Item = &'static SpinLock<Thread>> + '_ {
+        (0..).map_while(|index| self.threads.lock().get(index).copied())
+    }
+
+    // The threads of `pid` that have not exited
+    fn threads_of(&self, pid: Pid) -> impl Iterator<Item = &'static SpinLock<Thread>> + '_ {
+        self.threads().filter(move |slot| {
+            let thread = slot.lock();
+            thread.pid == pid && thread.is_live()
+        })
+    }
+
+    // None once the table is full: slot indices have to fit in the low PID_INDEX_BITS bits of a
+    // raw pid
+    fn alloc_slot(&self) -> Option<(Pid, &'static SpinLock<Process>)> {
+        if let Some((idx, slot)) = self.slots().enumerate().find(|(_, p)| !p.lock().alive) {
+            let generation = next_generation(idx, slot.lock().pid.generation);
+            return Some((Pid::new(idx, generation), slot));
+        }
+
+        let mut procs = self.procs.lock();
+        if procs.len() == 1 << PID_INDEX_BITS {
+            return None;
         }
+        let slot: &'static SpinLock<Process> = Box::leak(Box::new(SpinLock::new(Process::new())));
+        procs.push(slot);
+        Some((Pid::new(procs.len() - 1, 0), slot))
+    }
 
-        idle_proc.pid = idle_pid;
-        idle_proc.state = State::Runnable;
+    // A new slot comes with a kernel stack, which unused slots keep for the next thread
+    fn alloc_thread(&self) -> Result<(Tid, &'static SpinLock<Thread>), CreateError> {
+        if let Some((idx, slot)) = self
+            .threads()
+            .enumerate()
+            .find(|(_, t)| t.lock().state == State::Unused)
+        {
+            let generation = next_generation(idx, slot.lock().tid.generation);
+            return Ok((Tid::new(idx, generation), slot));
+        }
+
+        if self.threads.lock().len() == 1 << PID_INDEX_BITS {
+            return Err(CreateError::TableFull);
+        }
+        let thread = Thread::new().ok_or(CreateError::OutOfMemory)?;
+        let mut threads = self.threads.lock();
+        if threads.len() == 1 << PID_INDEX_BITS {
+            return Err(CreateError::TableFull);
+        }
+        let slot: &'static SpinLock<Thread> = Box::leak(Box::new(SpinLock::new(thread)));
+        threads.push(slot);
+        Ok((Tid::new(threads.len() - 1, 0), slot))
+    }
+
+    pub fn init(&self) {
+        let mut idle_proc = Process::new();
+
+        let page_table = new_page_table().expect("out of memory");
+
+        idle_proc.pid = Pid::idle();
+        idle_proc.name = Name::new("idle");
+        idle_proc.alive = true;
+        idle_proc.entry = crate::kernel_main as usize;
         idle_proc.page_table = page_table;
-        idle_proc.sscratch = [0, idle_proc.stack.as_ptr() as usize + KERNEL_STACK_SIZE];
 
-        self.procs[idle_pid.as_usize()].replace(idle_proc);
+        let slot: &'static SpinLock<Process> = Box::leak(Box::new(SpinLock::new(idle_proc)));
+        self.procs.lock().push(slot);
+
+        // the boot hart keeps running on the boot stack, the others start on the stacks of their
+        // idle threads
+        for hart in 0..MAX_HARTS {
+            let mut idle_thread = Thread::new().expect("out of memory");
+            idle_thread.tid = Tid::idle(hart);
+            idle_thread.pid = Pid::idle();
+            idle_thread.state = State::Runnable;
+            idle_thread.sscratch = [0, idle_thread.stack_top(), hart];
+
+            let slot: &'static SpinLock<Thread> = Box::leak(Box::new(SpinLock::new(idle_thread)));
+            self.threads.lock().push(slot);
+        }
+    }
+
+    // Makes the calling code the idle thread of `hart`, which it runs on
+    pub fn init_hart(&self, hart: usize) {
+        let tid = Tid::idle(hart);
+        *self.current[hart].lock() = tid;
+
+        let mut thread = self.thread(tid).lock();
+        let now = get_time();
+        thread.dispatched_at = now;
+        thread.accounted_at = now;
+        unsafe {
+            write_csr!("sscratch", &thread.sscratch);
+        }
     }
 
-    pub fn create_process(&self, pc: usize) -> Option<Pid> {
-        let idx = self
-            .procs
-            .iter()
-            .position(|p| p.borrow().state == State::Unused)?;
-        let mut proc = self.procs[idx].borrow_mut();
+    pub fn idle_stack_top(&self, hart: usize) -> usize {
+        self.thread(Tid::idle(hart)).lock().stack_top()
+    }
 
-        let page_table = alloc_pages(1);
+    pub fn create_user_process(
+        &self,
+        name: &str,
+        image: &'static [u8],
+        argv: &[&str],
+        priority: Priority,
+    ) -> Result<Pid, ElfError> {
+        let limits = self.slot(self.current_pid()).lock().limits;
+        let LoadedImage {
+            page_table,
+            entry,
+            sp,
+            pages,
+        } = load_image(image, argv, &limits)?;
 
-        let mut paddr = unsafe { KERNEL_BASE };
-        while paddr < unsafe { FREE_RAM_END } {
-            map_page(
+        let pid = self
+            .create(
+                name,
                 page_table,
-                VirtAddr::from_ptr(paddr),
-                PhysAddr::from_ptr(paddr),
-                PAGE_R | PAGE_W | PAGE_X,
+                user_entry as usize,
+                [entry, sp, 0],
+                priority,
+            )
+            .map_err(ElfError::from)?;
+        {
+            let mut proc = self.slot(pid).lock();
+            proc.entry = entry;
+            proc.image = image;
+            proc.argv = argv.iter().map(|arg| arg.to_string()).collect();
+            proc.pages = pages;
+        }
+
+        Ok(pid)
+    }
+
+    // Replaces the image of the current process, keeping its pid, IPC identity and the kernel
+    // stack of the calling thread; the other threads are terminated. The process is named after
+    // argv[0], or `name` without arguments. Only returns if the new image could not be loaded.
+    pub fn exec(
+        &self,
+        name: &str,
+        image: &'static [u8],
+        argv: Vec<String>,
+    ) -> Result<Infallible, ElfError> {
+        let pid = self.current_pid();
+        let limits = self.slot(pid).lock().limits;
+        let LoadedImage {
+            page_table,
+            entry,
+            sp,
+            pages,
+        } = {
+            let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+            load_image(image, &argv, &limits)?
+        };
+
+        self.kill_threads(pid, Some(self.current_tid()));
+        ipc::cancel(pid);
+        shm::detach_all(pid);
+
+        let old_page_table = {
+            let mut proc = self.slot(pid).lock();
+            proc.name = Name::new(argv.first().map_or(name, String::as_str));
+            proc.entry = entry;
+            proc.image = image;
+            proc.argv = argv;
+            proc.pages = pages;
+            proc.brk = USER_HEAP_BASE;
+            proc.heap_mapped = USER_HEAP_BASE;
+            mem::replace(&mut proc.page_table, page_table)
+        };
+
+        self.load_page_table(page_table);
+        self.retire(old_page_table);
+
+        // the old user stack and trap frame are abandoned, along with anything still owned on
+        // the kernel stack; the next trap starts from the top of the kernel stack again
+        unsafe {
+            asm!("
+            mv s0, {entry}
+            mv s1, {sp}
+            mv s2, zero
+            j {user_entry}
+            ",
+            entry = in(reg) entry,
+            sp = in(reg) sp,
+            user_entry = sym user_entry,
+            options(noreturn)
             );
-            paddr = unsafe { paddr.add(PAGE_SIZE) };
         }
+    }
+
+    fn create(
+        &self,
+        name: &str,
+        page_table: PhysAddr,
+        pc: usize,
+        args: [usize; 3],
+        priority: Priority,
+    ) -> Result<Pid, CreateError> {
+        let pid = self
+            .create_empty(name, page_table, pc)
+            .inspect_err(|_| free_page_table(page_table))?;
+        if let Err(err) = self.create_thread(pid, pc, args, SchedEntity::new(priority)) {
+            self.kill(pid);
+            return Err(err);
+        }
+        Ok(pid)
+    }
+
+    // A child of the current process without any threads yet
+    fn create_empty(
+        &self,
+        name: &str,
+        page_table: PhysAddr,
+        pc: usize,
+    ) -> Result<Pid, CreateError> {
+        let parent = self.current_pid();
+        let (limits, affinity) = {
+            let proc = self.slot(parent).lock();
+            (proc.limits, proc.affinity)
+        };
+        let (pid, slot) = self.alloc_slot().ok_or(CreateError::TableFull)?;
+        {
+            let mut proc = slot.lock();
+
+            proc.pid = pid;
+            proc.parent = parent;
+            proc.name = Name::new(name);
+            proc.alive = true;
+            proc.entry = pc;
+            proc.image = &[];
+            proc.argv = Vec::new();
+            proc.watchdog = Action::Report;
+            proc.affinity = affinity;
+            proc.start_time = get_time();
+            proc.stats = CpuStats::new();
+            proc.limits = limits;
+            proc.pages = 0;
+            proc.cpu_time = 0;
+            proc.page_table = page_table;
+            proc.brk = USER_HEAP_BASE;
+            proc.heap_mapped = USER_HEAP_BASE;
+        }
+
+        Ok(pid)
+    }
+
+    // `args` are handed to the entry point in s0-s2, see `user_entry`
+    fn create_thread(
+        &self,
+        pid: Pid,
+        pc: usize,
+        args: [usize; 3],
+        sched: SchedEntity,
+    ) -> Result<Tid, CreateError> {
+        let (tid, slot) = self.alloc_thread()?;
+        let affinity = self.slot(pid).lock().affinity;
+        let mut thread = slot.lock();
+
+        thread.tid = tid;
+        thread.pid = pid;
+        thread.state = State::Runnable;
+        thread.stats = CpuStats::new();
+        thread.sched = sched;
+        thread.since = get_time();
+        thread.exit_code = 0;
+        thread.killed = false;
+        thread.joiner.clear();
+        thread.context.ra = pc;
+        thread.context.s0 = args[0];
+        thread.context.s1 = args[1];
+        thread.context.s2 = args[2];
+        thread.context.sp = thread.stack_top();
+        thread.sscratch = [0, thread.stack_top(), 0];
+
+        // new threads go wherever there is the least to do
+        thread.affinity = affinity;
+        thread.hart = self.idlest(affinity);
+        self.enqueue(&mut thread, get_time());
+
+        Ok(tid)
+    }
 
-        proc.pid = Pid(idx);
-        proc.state = State::Runnable;
-        proc.page_table = page_table;
-        proc.context.ra = pc;
-        proc.context.sp = proc.stack.as_ptr() as usize + KERNEL_STACK_SIZE;
-        proc.sscratch = [0, proc.stack.as_ptr() as usize + KERNEL_STACK_SIZE];
+    // Starts a thread of the current process at `pc` in U-mode with stack pointer `sp` and `arg`
+    // in a0. It inherits the scheduling class and priority of the caller, so a real-time
+    // process has to pass admission once more for it.
+    pub fn create_user_thread(&self, pc: usize, sp: usize, arg: usize) -> Result<Tid, SchedError> {
+        let current = self.thread(self.current_tid()).lock().sched;
+        let pid = self.current_pid();
 
-        self.run_queue.enqueue(proc.pid);
+        let threads = self.threads_of(pid).count() + 1;
+        let mut others = self
+            .threads()
+            .map(|slot| slot.lock())
+            .filter(|thread| thread.pid != pid && thread.is_live())
+            .map(|thread| thread.sched.class);
+        self.local_queue()
+            .admit(&current.class, threads, &mut others)?;
 
-        Some(proc.pid)
+        let mut sched = SchedEntity::new(current.priority);
+        sched.set_class(current.class, get_time());
+        self.create_thread(pid, user_entry as usize, [pc, sp, arg], sched)
+            .map_err(SchedError::from)
+    }
+
+    pub fn parent(&self, pid: Pid) -> Option<Pid> {
+        self.get(pid).map(|slot| slot.lock().parent)
+    }
+
+    pub fn current_priority(&self) -> Priority {
+        self.thread(self.current_tid()).lock().sched.priority
+    }
+
+    // Applies to every thread of the process
+    pub fn set_priority(&self, pid: Pid, priority: Priority) -> bool {
+        if pid.is_idle() || self.get(pid).is_none() {
+            return false;
+        }
+
+        for slot in self.threads_of(pid) {
+            let mut thread = slot.lock();
+            thread.sched.set_priority(priority);
+            if self.dequeue(&thread) {
+                self.enqueue(&mut thread, get_time());
+            }
+        }
+
+        true
+    }
+
+    // Applies to every thread of the process; each of them counts towards admission
+    pub fn set_class(&self, pid: Pid, class: SchedClass) -> Result<(), SchedError> {
+        if pid.is_idle() {
+            return Err(SchedError::InvalidParameters);
+        }
+        self.get(pid).ok_or(SchedError::NoSuchProcess)?;
+        class.validate()?;
+
+        let threads = self.threads_of(pid).count();
+        let mut others = self
+            .threads()
+            .map(|slot| slot.lock())
+            .filter(|thread| thread.pid != pid && thread.is_live())
+            .map(|thread| thread.sched.class);
+        self.local_queue()
+            .admit(&class, threads.max(1), &mut others)?;
+
+        let now = get_time();
+        for slot in self.threads_of(pid) {
+            let mut thread = slot.lock();
+            let mut queued = self.dequeue(&thread);
+            if thread.state == State::Throttled {
+                cancel_timers(thread.tid);
+                thread.state = State::Runnable;
+                queued = true;
+            }
+
+            thread.sched.set_class(class, now);
+            if queued {
+                self.enqueue(&mut thread, now);
+            }
+        }
+
+        Ok(())
     }
 
     #[unsafe(naked)]
@@ -261,82 +1084,985 @@ impl ProcessManager {
     }
 
     pub fn switch(&self) {
-        let next = self.scheduler();
-        let mut current = self.current.borrow_mut();
+        let now = get_time();
+        self.wake_sleepers(now);
+        self.charge_current(now);
+
+        let next = self.scheduler(now);
+        let mut current = self.current[hart::id()].lock();
 
         if next == *current {
+            let mut thread = self.thread(next).lock();
+            thread.dispatched_at = now;
+            thread.accounted_at = now;
+            thread.since = now;
+            set_preemption(self.preempt_at(&thread, now));
             return;
         }
 
-        let mut current_proc = self.procs[current.as_usize()].borrow_mut();
-        let next_proc = self.procs[next.as_usize()].borrow();
+        let mut current_thread = self.thread(*current).lock();
+        let mut next_thread = self.thread(next).lock();
+
+        next_thread.dispatched_at = now;
+        next_thread.accounted_at = now;
+        next_thread.since = now;
+        next_thread.hart = hart::id();
+        set_preemption(self.preempt_at(&next_thread, now));
 
-        if current_proc.state == State::Runnable {
-            self.run_queue.enqueue(*current);
+        match current_thread.state {
+            State::Runnable | State::Throttled => current_thread.stats.involuntary_switches += 1,
+            State::Blocked | State::Sleeping => current_thread.stats.voluntary_switches += 1,
+            State::Unused | State::Exited => {}
         }
 
         *current = next;
 
-        let current_context = &mut current_proc.context as *mut Context;
-        let next_context = &next_proc.context as *const Context;
+        let current_context = &mut current_thread.context as *mut Context;
+        let next_context = &next_thread.context as *const Context;
 
-        let next_sscratch = &next_proc.sscratch;
+        next_thread.sscratch[2] = hart::id();
+        let next_sscratch = &next_thread.sscratch;
+        let page_table = self.slot(next_thread.pid).lock().page_table;
 
+        self.load_page_table(page_table);
+        unsafe {
+            write_csr!("sscratch", next_sscratch);
+        }
+
+        drop(current);
+        drop(current_thread);
+        drop(next_thread);
+
+        unsafe {
+            Self::switch_context(current_context, next_context);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ProcInfo> {
+        self.slots()
+            .filter_map(|slot| {
+                let proc = slot.lock();
+                if !proc.alive {
+                    return None;
+                }
+
+                let live: Vec<_> = self
+                    .threads()
+                    .map(|slot| slot.lock())
+                    .filter(|thread| thread.pid == proc.pid && thread.is_live())
+                    .collect();
+                let live: Vec<&Thread> = live.iter().map(|thread| &**thread).collect();
+                Some(ProcInfo::from_process(&proc, &live))
+            })
+            .collect()
+    }
+
+    // Summed over the harts
+    pub fn idle_time(&self) -> u64 {
+        (0..MAX_HARTS)
+            .map(|hart| self.thread(Tid::idle(hart)).lock().stats.cpu_time())
+            .sum()
+    }
+
+    pub fn current_page_table(&self) -> PhysAddr {
+        self.page_table(self.current_pid())
+    }
+
+    pub fn page_table(&self, pid: Pid) -> PhysAddr {
+        self.slot(pid).lock().page_table
+    }
+
+    // Switches this hart to `page_table` and frees the retired page tables it was the last to use
+    fn load_page_table(&self, page_table: PhysAddr) {
         unsafe {
             asm!("
             sfence.vma
             csrw satp, {satp}
             sfence.vma
-            csrw sscratch, {sscratch}
             ",
-            satp = in(reg) SATP_SV32 | (next_proc.page_table.as_usize() / PAGE_SIZE),
-            sscratch = in(reg) next_sscratch,
+            satp = in(reg) SATP_SV32 | (page_table.as_usize() / PAGE_SIZE),
             );
         }
+        *self.loaded[hart::id()].lock() = page_table;
+        self.reap();
+    }
+
+    // Frees `page_table` and the user pages in it as soon as no hart has it loaded. Threads of a
+    // dead process that still run elsewhere keep using it until they notice they were killed.
+    fn retire(&self, page_table: PhysAddr) {
+        self.retired.lock().push(page_table);
+        self.reap();
+    }
+
+    fn reap(&self) {
+        self.retired.lock().retain(|page_table| {
+            let loaded = self
+                .loaded
+                .iter()
+                .any(|loaded| loaded.lock().as_usize() == page_table.as_usize());
+            if loaded {
+                return true;
+            }
+            free_page_table(*page_table);
+            false
+        });
+    }
+
+    // Moves the program break of the current process, mapping zeroed pages as the heap grows.
+    // Returns the new break, or the current one for 0.
+    pub fn set_brk(&self, new_brk: usize) -> Option<usize> {
+        let mut proc = self.slot(self.current_pid()).lock();
+        if new_brk == 0 {
+            return Some(proc.brk);
+        }
 
-        drop(current);
-        drop(current_proc);
-        drop(next_proc);
+        if !(USER_HEAP_BASE..=USER_SHM_BASE).contains(&new_brk) {
+            return None;
+        }
+
+        let new_pages = (new_brk.saturating_sub(proc.heap_mapped)).div_ceil(PAGE_SIZE);
+        if proc.pages + new_pages > proc.limits.pages {
+            return None;
+        }
+
+        while proc.heap_mapped < new_brk {
+            let vaddr = VirtAddr::from_usize(proc.heap_mapped);
+            if !map_user_page(proc.page_table, vaddr, PAGE_U | PAGE_R | PAGE_W) {
+                return None;
+            }
+            proc.heap_mapped += PAGE_SIZE;
+            proc.pages += 1;
+        }
+
+        proc.brk = new_brk;
+        Some(new_brk)
+    }
 
+    // Drops stale translations for `len` bytes from `vaddr` in the address space of `pid`, here
+    // and on every other hart running one of its threads. Harts that switch to it later flush
+    // their TLB when they load its page table.
+    pub fn flush_tlb(&self, pid: Pid, vaddr: usize, len: usize) {
         unsafe {
-            Self::switch_context(current_context, next_context);
+            asm!("sfence.vma");
+        }
+
+        let me = hart::id();
+        let harts = (0..MAX_HARTS)
+            .filter(|&hart| hart != me && hart::is_online(hart))
+            .filter(|&hart| {
+                let tid = *self.current[hart].lock();
+                self.thread(tid).lock().pid == pid
+            })
+            .fold(0, |mask, hart| mask | 1 << hart);
+        hart::remote_sfence_vma(harts, vaddr, len);
+    }
+
+    // Moves the program break by `increment` bytes and returns the old one. Threads sharing a
+    // heap allocate with this, as reading and then setting the break would race.
+    pub fn sbrk(&self, increment: usize) -> Option<usize> {
+        let old = self.set_brk(0)?;
+        self.set_brk(old.checked_add(increment)?)?;
+        Some(old)
+    }
+
+    pub fn load_average(&self) -> LoadAvg {
+        *self.load.lock()
+    }
+
+    // Called on a trap from U-mode: the time since the last accounting was spent in user space
+    pub fn account_user_time(&self) {
+        self.account(
+            &mut self.thread(self.current_tid()).lock(),
+            get_time(),
+            true,
+        );
+    }
+
+    // Called right before returning to U-mode
+    pub fn account_system_time(&self) {
+        self.account(
+            &mut self.thread(self.current_tid()).lock(),
+            get_time(),
+            false,
+        );
+    }
+
+    // Charges a thread and its process for the time since the thread was last accounted. A
+    // killed thread may outlive its process, whose slot may have a new occupant by then.
+    fn account(&self, thread: &mut Thread, now: u64, user: bool) {
+        let delta = thread.account(now, user);
+        if let Some(slot) = self.get(thread.pid) {
+            slot.lock().cpu_time += delta;
+        }
+    }
+
+    pub fn cpu_limit_exceeded(&self) -> bool {
+        let proc = self.slot(self.current_pid()).lock();
+        proc.cpu_time > proc.limits.cpu_time
+    }
+
+    pub fn limits(&self, pid: Pid) -> Option<Limits> {
+        self.get(pid).map(|slot| slot.lock().limits)
+    }
+
+    pub fn set_limit(&self, pid: Pid, resource: Resource, value: u64) -> bool {
+        let Some(slot) = self.get(pid) else {
+            return false;
+        };
+        slot.lock().limits.set(resource, value);
+        true
+    }
+
+    pub fn set_watchdog(&self, pid: Pid, action: Action) -> bool {
+        let Some(slot) = self.get(pid) else {
+            return false;
+        };
+        slot.lock().watchdog = action;
+        true
+    }
+
+    // Queued threads move over right away, running ones when they are next switched out
+    pub fn set_affinity(&self, pid: Pid, affinity: Affinity) -> bool {
+        if pid.is_idle() {
+            return false;
+        }
+        let Some(slot) = self.get(pid) else {
+            return false;
+        };
+        slot.lock().affinity = affinity;
+
+        let now = get_time();
+        for slot in self.threads_of(pid) {
+            let mut thread = slot.lock();
+            thread.affinity = affinity;
+            if !affinity.contains(thread.hart) && self.dequeue(&thread) {
+                self.enqueue(&mut thread, now);
+            }
         }
+        true
+    }
+
+    pub fn thread_count(&self, pid: Pid) -> usize {
+        self.threads_of(pid).count()
+    }
+
+    pub fn children(&self, pid: Pid) -> usize {
+        self.slots()
+            .filter(|slot| {
+                let proc = slot.lock();
+                proc.alive && proc.parent == pid && proc.pid != pid
+            })
+            .count()
+    }
+
+    // Percentage of the time since boot the harts spent running anything but their idle threads
+    pub fn utilization(&self) -> u64 {
+        let total = get_time() * hart::online() as u64;
+        if total == 0 {
+            return 0;
+        }
+        100 - self.idle_time().min(total) * 100 / total
+    }
+
+    // Ends the current process with all of its threads
+    pub fn exit(&self) -> ! {
+        if self.current_tid().is_idle() {
+            panic!("idle process tried to exit");
+        }
+
+        self.kill(self.current_pid());
+        self.switch();
+
+        unreachable!();
+    }
+
+    // Ends a process with all of its threads; the current one keeps running until it switches
+    pub fn kill(&self, pid: Pid) {
+        if pid.is_idle() || self.get(pid).is_none() {
+            return;
+        }
+
+        self.kill_threads(pid, None);
+        self.slot(pid).lock().alive = false;
+        ipc::release(pid);
+        futex::release(pid);
+        // shared pages are unmapped first so that they are not freed with the rest
+        shm::detach_all(pid);
+        self.retire(self.page_table(pid));
+    }
+
+    // Starts the program of another, live process over under the same pid, so that whoever
+    // talks to it over IPC keeps working; messages already queued for it are kept for the new
+    // instance
+    pub fn restart(&self, pid: Pid) -> Result<(), ElfError> {
+        let slot = self.slot(pid);
+        let (image, argv, limits) = {
+            let proc = slot.lock();
+            (proc.image, proc.argv.clone(), proc.limits)
+        };
+        let sched = self
+            .threads_of(pid)
+            .next()
+            .map_or(SchedEntity::new(Priority::NORMAL), |slot| slot.lock().sched);
+
+        let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let LoadedImage {
+            page_table,
+            entry,
+            sp,
+            pages,
+        } = load_image(image, &argv, &limits)?;
+
+        self.kill_threads(pid, None);
+        ipc::cancel(pid);
+        futex::release(pid);
+        shm::detach_all(pid);
+
+        let old_page_table = {
+            let mut proc = slot.lock();
+            proc.entry = entry;
+            proc.pages = pages;
+            proc.brk = USER_HEAP_BASE;
+            proc.heap_mapped = USER_HEAP_BASE;
+            mem::replace(&mut proc.page_table, page_table)
+        };
+        // threads of the old instance may still be on their way off other harts
+        self.retire(old_page_table);
+
+        let mut main = SchedEntity::new(sched.priority);
+        main.set_class(sched.class, get_time());
+        if let Err(err) = self.create_thread(pid, user_entry as usize, [entry, sp, 0], main) {
+            self.kill(pid);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    // Everything a checkpoint of `pid` needs but the contents of its pages. Not for a process
+    // running anywhere: the user registers of a thread are only saved while it is off the CPU.
+    pub fn checkpoint(&self, pid: Pid) -> Result<Checkpoint, CheckpointError> {
+        if pid == self.current_pid() || pid.is_idle() {
+            return Err(CheckpointError::NoSuchProcess);
+        }
+
+        let proc = self.get(pid).ok_or(CheckpointError::NoSuchProcess)?.lock();
+        if self
+            .threads_of(pid)
+            .any(|slot| self.running_elsewhere(slot.lock().tid).is_some())
+        {
+            return Err(CheckpointError::Running);
+        }
+        let frames = self
+            .threads_of(pid)
+            .map(|slot| slot.lock().user_frame())
+            .collect();
+
+        Ok(Checkpoint {
+            name: proc.name,
+            entry: proc.entry,
+            brk: proc.brk,
+            heap_mapped: proc.heap_mapped,
+            page_table: proc.page_table,
+            inbox: proc.ipc.inbox,
+            senders: proc.ipc.senders.iter().copied().collect(),
+            frames,
+        })
+    }
+
+    // Starts a child of the current process from a checkpoint whose `pages` pages are mapped in
+    // its page table already, with a thread for each saved set of user registers. The page
+    // table is freed if the process cannot be created.
+    pub fn restore(&self, checkpoint: &Checkpoint, pages: usize) -> Result<Pid, CheckpointError> {
+        let pid = self
+            .create_empty(
+                checkpoint.name.as_str(),
+                checkpoint.page_table,
+                checkpoint.entry,
+            )
+            .inspect_err(|_| free_page_table(checkpoint.page_table))?;
+        {
+            let mut proc = self.slot(pid).lock();
+            proc.pages = pages;
+            proc.brk = checkpoint.brk;
+            proc.heap_mapped = checkpoint.heap_mapped;
+            proc.ipc.inbox = checkpoint.inbox;
+            proc.ipc.senders = checkpoint.senders.iter().copied().collect();
+        }
+
+        let priority = self.current_priority();
+        for frame in &checkpoint.frames {
+            let tid = match self.create_thread(
+                pid,
+                user_resume as usize,
+                [0; 3],
+                SchedEntity::new(priority),
+            ) {
+                Ok(tid) => tid,
+                Err(err) => {
+                    self.kill(pid);
+                    return Err(err.into());
+                }
+            };
+
+            let mut thread = self.thread(tid).lock();
+            let mut frame = *frame;
+            frame.sstatus = SSTATUS_SPIE;
+            unsafe { ptr::write(thread.frame(), frame) };
+            thread.context.sp = thread.frame() as usize;
+        }
+
+        Ok(pid)
+    }
+
+    // Ends the current thread, keeping `code` for whoever joins it; the last thread of a
+    // process takes the process with it
+    pub fn exit_thread(&self, code: usize) -> ! {
+        let me = self.current_tid();
+        let pid = self.current_pid();
+        if self.threads_of(pid).count() == 1 {
+            self.exit();
+        }
+
+        {
+            let mut thread = self.thread(me).lock();
+            thread.state = State::Exited;
+            thread.exit_code = code;
+            thread.joiner.wake_all();
+        }
+
+        self.switch();
+
+        unreachable!();
+    }
+
+    // Waits for another thread of the current process to exit and returns its exit code
+    pub fn join(&self, tid: Tid) -> Result<usize, ThreadError> {
+        let me = self.current_tid();
+        if tid == me {
+            return Err(ThreadError::Deadlock);
+        }
+        let slot = self
+            .get_thread(tid)
+            .filter(|slot| slot.lock().pid == self.current_pid())
+            .ok_or(ThreadError::NoSuchThread)?;
+
+        let wait = {
+            let thread = slot.lock();
+            if !thread.joiner.is_empty() {
+                return Err(ThreadError::AlreadyJoined);
+            }
+            let wait = thread.state != State::Exited;
+            if wait {
+                thread.joiner.prepare_wait();
+            }
+            wait
+        };
+        if wait {
+            self.switch();
+        }
+
+        let mut thread = slot.lock();
+        if thread.tid != tid || thread.state != State::Exited {
+            return Err(ThreadError::NoSuchThread);
+        }
+        thread.state = State::Unused;
+        self.slot(thread.pid).lock().stats.add(&thread.stats);
+        Ok(thread.exit_code)
+    }
+
+    // Tears down every thread of `pid` but `except`, whatever it is doing
+    fn kill_threads(&self, pid: Pid, except: Option<Tid>) {
+        for slot in self.threads() {
+            let mut thread = slot.lock();
+            if thread.pid != pid || Some(thread.tid) == except || thread.state == State::Unused {
+                continue;
+            }
+            // its kernel stack is in use until it leaves the CPU
+            if self.running_elsewhere(thread.tid).is_some() {
+                thread.killed = true;
+                continue;
+            }
+
+            self.dequeue(&thread);
+            cancel_timers(thread.tid);
+            thread.state = State::Unused;
+            self.slot(pid).lock().stats.add(&thread.stats);
+        }
+    }
+
+    // Ends the current thread if it was killed while it ran on this hart and another one tore its
+    // process down
+    pub fn exit_if_killed(&self) {
+        {
+            let mut thread = self.thread(self.current_tid()).lock();
+            if !thread.killed {
+                return;
+            }
+            thread.killed = false;
+            thread.state = State::Unused;
+            // the process is gone unless only this thread was, by exec
+            if let Some(slot) = self.get(thread.pid) {
+                slot.lock().stats.add(&thread.stats);
+            }
+        }
+
+        self.switch();
+
+        unreachable!();
     }
 
     pub fn block_current(&self) {
-        let mut proc = self.procs[self.current_pid().as_usize()].borrow_mut();
-        if proc.state == State::Runnable {
-            proc.state = State::Blocked;
+        let mut thread = self.thread(self.current_tid()).lock();
+        if thread.state == State::Runnable {
+            thread.state = State::Blocked;
         }
     }
 
+    // Wakes the thread of `pid` blocked in IPC, if any
     pub fn unblock(&self, pid: Pid) {
         if pid.is_idle() {
             return;
         }
 
-        let mut proc = self.procs[pid.as_usize()].borrow_mut();
-        if proc.state == State::Blocked {
-            proc.state = State::Runnable;
-            self.run_queue.enqueue(pid);
+        if let Some(slot) = self.get(pid) {
+            slot.lock().ipc.waiters.wake_all();
+        }
+    }
+
+    // Makes a blocked or sleeping thread runnable before its deadline; returns whether it was
+    // waiting at all
+    pub fn wake(&self, tid: Tid) -> bool {
+        let Some(slot) = self.get_thread(tid) else {
+            return false;
+        };
+        let mut thread = slot.lock();
+        if !matches!(thread.state, State::Blocked | State::Sleeping) {
+            return false;
+        }
+
+        if thread.state == State::Sleeping {
+            cancel_timers(tid);
+        }
+        let now = get_time();
+        thread.state = State::Runnable;
+        thread.since = now;
+        self.wake_queued(&mut thread, now);
+        true
+    }
+
+    pub fn sleep_current(&self) {
+        let mut thread = self.thread(self.current_tid()).lock();
+        if thread.state == State::Runnable {
+            thread.state = State::Sleeping;
+        }
+    }
+
+    fn wake_sleepers(&self, now: u64) {
+        let current = self.current_tid();
+        while let Some(tid) = pop_expired() {
+            let Some(slot) = self.get_thread(tid) else {
+                continue;
+            };
+            let mut thread = slot.lock();
+            if !matches!(thread.state, State::Sleeping | State::Throttled) {
+                continue;
+            }
+
+            thread.state = State::Runnable;
+            thread.since = now;
+            if tid != current {
+                self.wake_queued(&mut thread, now);
+            }
         }
     }
 
-    fn scheduler(&self) -> Pid {
-        let next = self.run_queue.dequeue();
-        if let Some(pid) = next {
-            return pid;
+    // Charges the running time to the current thread and hands it back to the scheduler.
+    // A thread the scheduler holds back stays throttled until the timer releases it.
+    fn charge_current(&self, now: u64) {
+        let current = self.current_tid();
+        let mut thread = self.thread(current).lock();
+
+        let ran = now - thread.dispatched_at;
+        self.account(&mut thread, now, false);
+        thread.since = now;
+
+        if current.is_idle() {
+            return;
+        }
+
+        match thread.state {
+            State::Runnable => {
+                let queue = self.local_queue();
+                if let Some(release) = queue.on_tick(current, &mut thread.sched, ran, now) {
+                    thread.state = State::Throttled;
+                    wake_at(Instant::from_ticks(release), current);
+                } else if !thread.affinity.contains(hart::id()) {
+                    // its affinity changed while it ran
+                    queue.dequeue(current);
+                    self.enqueue(&mut thread, now);
+                }
+            }
+            State::Blocked | State::Sleeping | State::Unused | State::Exited => {
+                self.local_queue().on_block(&mut thread.sched, ran)
+            }
+            State::Throttled => {}
         }
+    }
 
-        let current = self.current_pid();
-        if self.procs[current.as_usize()].borrow().state == State::Runnable {
-            return current;
+    fn preempt_at(&self, thread: &Thread, now: u64) -> Option<Instant> {
+        self.local_queue()
+            .preempt_at(&thread.sched, now)
+            .map(Instant::from_ticks)
+    }
+
+    // Runs after the current thread has been put back on the run queue, so the queue length is
+    // the number of runnable threads
+    fn update_load(&self, now: u64) {
+        let mut next_sample = self.next_load_sample.lock();
+        if now < *next_sample {
+            return;
         }
 
-        Pid::idle()
+        let runnable = self.runnable() as u64;
+        let mut load = self.load.lock();
+        let period = us_to_ticks(LOADAVG_PERIOD_US);
+        while *next_sample <= now {
+            load.update(runnable);
+            *next_sample += period;
+        }
     }
+
+    fn scheduler(&self, now: u64) -> Tid {
+        self.update_load(now);
+        self.balance(now);
+
+        let hart = hart::id();
+        let queue = self.local_queue();
+        if queue.is_empty() {
+            self.steal(hart, now);
+        }
+        queue.pick_next(now).unwrap_or(Tid::idle(hart))
+    }
+
+    fn local_queue(&self) -> &'static dyn Scheduler {
+        self.schedulers[hart::id()]
+    }
+
+    // Threads queued on all harts
+    fn runnable(&self) -> usize {
+        self.schedulers.iter().map(|queue| queue.len()).sum()
+    }
+
+    // Whether this hart has something to run
+    pub fn has_runnable(&self) -> bool {
+        !self.local_queue().is_empty()
+    }
+
+    fn may_run_on(&self, tid: Tid, hart: usize) -> bool {
+        self.thread(tid).lock().affinity.contains(hart)
+    }
+
+    // The hart with the shortest run queue of those in `affinity`
+    fn idlest(&self, affinity: Affinity) -> usize {
+        affinity
+            .harts()
+            .min_by_key(|&hart| self.schedulers[hart].len())
+            .unwrap_or(hart::id())
+    }
+
+    // Queues a runnable thread on the hart it last ran on, where its cache may still be warm,
+    // unless its affinity no longer allows that hart
+    fn enqueue(&self, thread: &mut Thread, now: u64) {
+        self.place(thread);
+        self.schedulers[thread.hart].enqueue(thread.tid, &mut thread.sched, now);
+        hart::send_reschedule(thread.hart);
+    }
+
+    fn wake_queued(&self, thread: &mut Thread, now: u64) {
+        self.place(thread);
+        self.schedulers[thread.hart].on_wake(thread.tid, &mut thread.sched, now);
+        hart::send_reschedule(thread.hart);
+    }
+
+    fn place(&self, thread: &mut Thread) {
+        if !thread.affinity.contains(thread.hart) || !hart::is_online(thread.hart) {
+            let hart = self.idlest(thread.affinity);
+            self.move_to(thread, hart);
+        }
+    }
+
+    fn move_to(&self, thread: &mut Thread, hart: usize) {
+        self.schedulers[thread.hart].on_leave(&mut thread.sched);
+        thread.hart = hart;
+        self.schedulers[hart].on_arrive(&mut thread.sched);
+    }
+
+    fn dequeue(&self, thread: &Thread) -> bool {
+        self.schedulers[thread.hart].dequeue(thread.tid)
+    }
+
+    // Takes a thread that may run on `hart` from the other hart with the most queued, if any
+    fn steal(&self, hart: usize, now: u64) {
+        let mut victims: [(usize, usize); MAX_HARTS] =
+            array::from_fn(|other| (self.schedulers[other].len(), other));
+        victims.sort_unstable_by_key(|&(len, _)| Reverse(len));
+
+        for (len, victim) in victims {
+            if len == 0 || victim == hart {
+                continue;
+            }
+            let stolen = self.schedulers[victim].steal(&mut |tid| self.may_run_on(tid, hart));
+            if let Some(tid) = stolen {
+                self.migrate(tid, hart, now);
+                return;
+            }
+        }
+    }
+
+    fn migrate(&self, tid: Tid, hart: usize, now: u64) {
+        let mut thread = self.thread(tid).lock();
+        self.move_to(&mut thread, hart);
+        self.schedulers[hart].enqueue(tid, &mut thread.sched, now);
+        hart::send_reschedule(hart);
+    }
+
+    // Every SCHED_BALANCE_PERIOD_US, moves threads from the longest run queue to the shortest
+    // until they differ by at most one, or nothing on the longer one may move
+    fn balance(&self, now: u64) {
+        {
+            let mut next_balance = self.next_balance.lock();
+            if now < *next_balance {
+                return;
+            }
+            *next_balance = now + us_to_ticks(SCHED_BALANCE_PERIOD_US);
+        }
+
+        loop {
+            let harts = || Affinity::ALL.harts();
+            let len = |hart: usize| self.schedulers[hart].len();
+            let (Some(busiest), Some(idlest)) = (
+                harts().max_by_key(|&h| len(h)),
+                harts().min_by_key(|&h| len(h)),
+            ) else {
+                return;
+            };
+            if len(busiest) <= len(idlest) + 1 {
+                return;
+            }
+
+            let stolen = self.schedulers[busiest].steal(&mut |tid| self.may_run_on(tid, idlest));
+            let Some(tid) = stolen else {
+                return;
+            };
+            self.migrate(tid, idlest, now);
+        }
+    }
+}
+
+// Drops to U-mode at the entry point in s0 with the user stack pointer in s1 and s2 in a0
+#[unsafe(naked)]
+#[repr(align(4))]
+unsafe extern "C" fn user_entry() -> ! {
+    naked_asm!(
+        "
+        call {leave_kernel}
+        csrw sepc, s0
+        li t0, {sstatus}
+        csrw sstatus, t0
+        mv sp, s1
+        mv a0, s2
+        sret
+        ",
+        leave_kernel = sym leave_kernel,
+        sstatus = const SSTATUS_SPIE,
+    )
+}
+
+// Drops to U-mode with the registers in the trap frame at sp, see `restore`
+#[unsafe(naked)]
+#[repr(align(4))]
+unsafe extern "C" fn user_resume() -> ! {
+    naked_asm!(
+        "
+        call {leave_kernel}
+        j {trap_return}
+        ",
+        leave_kernel = sym leave_kernel,
+        trap_return = sym trap_return,
+    )
+}
+
+extern "C" fn leave_kernel() {
+    PM.account_system_time();
+    unlock_kernel();
+}
+
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+// Runs `f` in a kernel thread, as a child process of the current one that exits when `f`
+// returns. The thread runs in S-mode outside the kernel lock with interrupts enabled, so it is
+// preempted like user code and goes through `with_kernel` for anything in the kernel. Nothing
+// but the self-tests starts one so far.
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+pub fn spawn<F>(name: &str, f: F) -> Option<Pid>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let page_table = new_page_table()?;
+    let main: Box<ThreadMain> = Box::new(Box::new(f));
+    let arg = Box::into_raw(main);
+    let pid = PM.create(
+        name,
+        page_table,
+        thread_trampoline as usize,
+        [arg as usize, 0, 0],
+        Priority::NORMAL,
+    );
+    if pid.is_err() {
+        drop(unsafe { Box::from_raw(arg) });
+    }
+    pid.ok()
+}
+
+// Calls `f` inside the kernel from a kernel thread. Not to be nested.
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+pub fn with_kernel<T>(f: impl FnOnce() -> T) -> T {
+    irq_disable();
+    lock_kernel();
+    PM.account_user_time();
+    PM.exit_if_killed();
+    let ret = f();
+    PM.account_system_time();
+    unlock_kernel();
+    irq_enable();
+    ret
+}
+
+// Leaves the kernel like `user_entry`, but stays in S-mode to run the closure in s0
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+#[unsafe(naked)]
+#[repr(align(4))]
+unsafe extern "C" fn thread_trampoline() -> ! {
+    naked_asm!(
+        "
+        call {leave_kernel}
+        csrsi sstatus, {sie}
+        mv a0, s0
+        j {thread_main}
+        ",
+        leave_kernel = sym leave_kernel,
+        sie = const SSTATUS_SIE,
+        thread_main = sym thread_main,
+    )
+}
+
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+extern "C" fn thread_main(arg: *mut ThreadMain) -> ! {
+    let main = unsafe { Box::from_raw(arg) };
+    main();
+
+    irq_disable();
+    lock_kernel();
+    PM.account_user_time();
+    // killed while it ran here, its process is gone already and there is nothing to exit
+    PM.exit_if_killed();
+    PM.exit();
 }
 
-unsafe impl Sync for ProcessManager {}
+// An address space with a program and its arguments loaded, not in use by any process yet
+struct LoadedImage {
+    page_table: PhysAddr,
+    entry: usize,
+    sp: usize,
+    pages: usize,
+}
+
+// Loads `image` into a fresh address space to run under `limits`; nothing stays allocated if it
+// does not fit
+fn load_image(image: &[u8], argv: &[&str], limits: &Limits) -> Result<LoadedImage, ElfError> {
+    let page_table = new_page_table().ok_or(ElfError::OutOfMemory)?;
+    let loaded = elf::load(page_table, image).and_then(|entry| {
+        let sp = map_user_stack(page_table, argv)?;
+        let pages = check_pages(page_table, limits)?;
+        Ok(LoadedImage {
+            page_table,
+            entry,
+            sp,
+            pages,
+        })
+    });
+    if loaded.is_err() {
+        free_page_table(page_table);
+    }
+    loaded
+}
+
+// Counts the user pages of a freshly loaded image against the limits it is going to run under
+fn check_pages(page_table: PhysAddr, limits: &Limits) -> Result<usize, ElfError> {
+    let pages = count_user_pages(page_table);
+    if pages > limits.pages {
+        return Err(ElfError::PageLimit);
+    }
+    Ok(pages)
+}
+
+// Lays out the arguments at the top of the stack and returns the initial sp, pointing to
+// argc followed by the NULL-terminated argv array
+fn map_user_stack(page_table: PhysAddr, argv: &[&str]) -> Result<usize, ElfError> {
+    for i in 1..=USER_STACK_PAGES {
+        let vaddr = VirtAddr::from_usize(USER_STACK_TOP - i * PAGE_SIZE);
+        if !map_user_page(page_table, vaddr, PAGE_U | PAGE_R | PAGE_W) {
+            return Err(ElfError::OutOfMemory);
+        }
+    }
+
+    let strings_size: usize = argv.iter().map(|arg| arg.len() + 1).sum();
+    let table_size = (argv.len() + 2) * size_of::<usize>();
+    if strings_size + table_size > PAGE_SIZE {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let mut table = Vec::with_capacity(argv.len() + 2);
+    table.push(argv.len());
+
+    let mut str_addr = USER_STACK_TOP - strings_size;
+    for arg in argv {
+        copy_to_user(page_table, VirtAddr::from_usize(str_addr), arg.as_bytes());
+        copy_to_user(page_table, VirtAddr::from_usize(str_addr + arg.len()), &[0]);
+        table.push(str_addr);
+        str_addr += arg.len() + 1;
+    }
+    table.push(0);
+
+    let sp = (USER_STACK_TOP - strings_size - table_size) & !0xf;
+    for (i, value) in table.iter().enumerate() {
+        let addr = VirtAddr::from_usize(sp + i * size_of::<usize>());
+        copy_to_user(page_table, addr, &value.to_le_bytes());
+    }
+
+    Ok(sp)
+}
+
+// The boot context of each hart turns into its idle thread once the hart is up. It halts the
+// hart until an interrupt arrives whenever nothing is runnable, letting the other harts into the
+// kernel meanwhile.
+pub fn idle() -> ! {
+    loop {
+        PM.switch();
+        if take_watchdog_due() {
+            watchdog::check();
+        }
+
+        irq_disable();
+        if !PM.has_runnable() && !has_expired() {
+            unlock_kernel();
+            unsafe { asm!("wfi") };
+            lock_kernel();
+        }
+        irq_enable();
+    }
+}
 
-pub static PM: ProcessManager = ProcessManager::new();
+pub static PM: ProcessManager = ProcessManager::new(sched::run_queues());
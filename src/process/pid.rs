@@ -0,0 +1,142 @@
+use core::fmt;
+
+use abi::PID_INDEX_BITS;
+
+use crate::constants::MAX_HARTS;
+
+const PID_GENERATION_BITS: usize = usize::BITS as usize - PID_INDEX_BITS;
+
+// raw ids from here up read as a negated errno in user space, see `syscall` in the user library
+const RAW_ID_LIMIT: usize = -4095isize as usize;
+
+// The generation that follows `generation` in slot `index`, skipping those whose raw ids user
+// space would take for an error
+pub fn next_generation(index: usize, generation: usize) -> usize {
+    let next = (generation + 1) % (1 << PID_GENERATION_BITS);
+    if Pid::new(index, next).as_raw() >= RAW_ID_LIMIT {
+        0
+    } else {
+        next
+    }
+}
+
+// A slot index in the process table plus the number of times the slot has been reused, so that a
+// stale Pid never refers to the process that took its slot over
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pid {
+    pub(super) index: usize,
+    pub(super) generation: usize,
+}
+
+impl Pid {
+    pub const fn new(index: usize, generation: usize) -> Self {
+        Pid { index, generation }
+    }
+
+    pub const fn idle() -> Self {
+        Pid::new(0, 0)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self == &Pid::idle()
+    }
+
+    // How user space sees a Pid: the index in the low PID_INDEX_BITS bits, the generation above
+    pub fn as_raw(&self) -> usize {
+        self.index | self.generation << PID_INDEX_BITS
+    }
+
+    pub fn from_raw(raw: usize) -> Self {
+        Pid::new(raw & ((1 << PID_INDEX_BITS) - 1), raw >> PID_INDEX_BITS)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+// Threads are numbered like processes, in a table of their own
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tid {
+    pub(super) index: usize,
+    pub(super) generation: usize,
+}
+
+impl Tid {
+    pub const fn new(index: usize, generation: usize) -> Self {
+        Tid { index, generation }
+    }
+
+    // Each hart has an idle thread, in the slot of its hart id
+    pub const fn idle(hart: usize) -> Self {
+        Tid::new(hart, 0)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.index < MAX_HARTS
+    }
+
+    pub fn as_raw(&self) -> usize {
+        self.index | self.generation << PID_INDEX_BITS
+    }
+
+    pub fn from_raw(raw: usize) -> Self {
+        Tid::new(raw & ((1 << PID_INDEX_BITS) - 1), raw >> PID_INDEX_BITS)
+    }
+}
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LAST_GENERATION: usize = (1 << PID_GENERATION_BITS) - 1;
+    const LAST_INDEX: usize = (1 << PID_INDEX_BITS) - 1;
+
+    #[test]
+    fn raw_ids_round_trip() {
+        let pid = Pid::new(5, 3);
+        assert_eq!(pid.as_raw(), 5 | 3 << PID_INDEX_BITS);
+        assert_eq!(Pid::from_raw(pid.as_raw()), pid);
+
+        let tid = Tid::new(LAST_INDEX, 7);
+        assert_eq!(Tid::from_raw(tid.as_raw()), tid);
+    }
+
+    #[test]
+    fn idle_ids() {
+        assert!(Pid::idle().is_idle());
+        assert!(!Pid::new(0, 1).is_idle());
+        assert!(Tid::idle(MAX_HARTS - 1).is_idle());
+        assert!(!Tid::new(MAX_HARTS, 0).is_idle());
+    }
+
+    #[test]
+    fn generations_count_up_and_wrap() {
+        assert_eq!(next_generation(1, 0), 1);
+        assert_eq!(next_generation(1, 41), 42);
+        assert_eq!(next_generation(1, LAST_GENERATION - 1), LAST_GENERATION);
+        assert_eq!(next_generation(1, LAST_GENERATION), 0);
+    }
+
+    // in the last generation, the top slots would have raw ids in the errno range
+    #[test]
+    fn generations_skip_raw_ids_read_as_errors() {
+        assert!(Pid::new(LAST_INDEX, LAST_GENERATION).as_raw() >= RAW_ID_LIMIT);
+        assert_eq!(next_generation(LAST_INDEX, LAST_GENERATION - 1), 0);
+
+        for index in [0, 1, MAX_HARTS, LAST_INDEX / 2, LAST_INDEX - 1, LAST_INDEX] {
+            for generation in [0, LAST_GENERATION - 2, LAST_GENERATION - 1, LAST_GENERATION] {
+                let next = next_generation(index, generation);
+                assert!(Pid::new(index, next).as_raw() < RAW_ID_LIMIT);
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+pub struct Program {
+    pub name: &'static str,
+    pub image: &'static [u8],
+}
+
+macro_rules! program {
+    ($name:literal) => {
+        Program {
+            name: $name,
+            image: include_bytes!(concat!(env!("USER_BIN_DIR"), "/", $name)),
+        }
+    };
+}
+
+// ELF images of the binaries in user/, built by build.rs; SYS_SPAWN and SYS_EXEC look
+// programs up here by name
+pub static PROGRAMS: &[Program] = &[
+    program!("init"),
+    program!("display"),
+    program!("playground"),
+    program!("hello"),
+    program!("selftest"),
+];
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
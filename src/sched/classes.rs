@@ -0,0 +1,523 @@
+use alloc::collections::VecDeque;
+
+use super::{SchedClass, SchedEntity, SchedError, Scheduler};
+use crate::{
+    constants::{
+        SCHED_BOOST_PERIOD_US, SCHED_FAIR_SHARE, SCHED_LEVELS, SCHED_RT_BANDWIDTH,
+        SCHED_RT_PROCESS_BANDWIDTH, SCHED_SLICE_US, SCHED_WEIGHT_UNIT,
+    },
+    process::Tid,
+    sync::SpinLock,
+    timer::us_to_ticks,
+};
+
+// EDF for real-time processes, then a multi-level feedback queue and weighted fair sharing, the
+// latter with a guaranteed share of what the former two compete for
+pub struct Classes {
+    queues: SpinLock<Queues>,
+}
+
+struct Queues {
+    // real-time processes sorted by absolute deadline
+    realtime: VecDeque<(u64, Tid)>,
+    // MLFQ levels; each process comes with the level a boost brings it back to
+    levels: [VecDeque<(Tid, usize)>; SCHED_LEVELS],
+    // fair-class processes sorted by virtual runtime
+    fair: VecDeque<(u64, Tid)>,
+    // virtual runtime of the last fair-class process picked; processes coming back from a sleep
+    // start no earlier so that they cannot monopolize the CPU to catch up
+    min_vruntime: u64,
+    // CPU time MLFQ and fair-class processes got while the other class had processes waiting,
+    // halved at every boost so that old contention is forgotten
+    contended: (u64, u64),
+    // the last of `BOOSTS` this run queue went through
+    epoch: u64,
+}
+
+// Boosts are counted for all harts at once, so that a thread moving to another run queue finds
+// the same epoch there. Entities of processes that were not queued catch up lazily.
+struct Boosts {
+    last: u64,
+    epoch: u64,
+}
+
+static BOOSTS: SpinLock<Boosts> = SpinLock::new(Boosts { last: 0, epoch: 0 });
+
+// The number of boosts so far, starting a new one if SCHED_BOOST_PERIOD_US passed by `now`
+fn boost_epoch(now: Option<u64>) -> u64 {
+    let mut boosts = BOOSTS.lock();
+    if let Some(now) = now
+        && now.saturating_sub(boosts.last) >= us_to_ticks(SCHED_BOOST_PERIOD_US)
+    {
+        boosts.last = now;
+        boosts.epoch += 1;
+    }
+    boosts.epoch
+}
+
+impl Classes {
+    pub const fn new() -> Self {
+        Classes {
+            queues: SpinLock::new(Queues {
+                realtime: VecDeque::new(),
+                levels: [const { VecDeque::new() }; SCHED_LEVELS],
+                fair: VecDeque::new(),
+                min_vruntime: 0,
+                contended: (0, 0),
+                epoch: 0,
+            }),
+        }
+    }
+}
+
+impl Queues {
+    // Goes through the boost that started since this run queue last looked, if any
+    fn sync(&mut self, now: Option<u64>) {
+        let epoch = boost_epoch(now);
+        if self.epoch != epoch {
+            self.epoch = epoch;
+            self.boost();
+        }
+    }
+
+    fn catch_up(&mut self, se: &mut SchedEntity) {
+        self.sync(None);
+        if se.epoch != self.epoch {
+            se.epoch = self.epoch;
+            se.level = se.priority.as_usize();
+            se.slice_used = 0;
+        }
+    }
+
+    // Moves every MLFQ process back to the level of its priority so that CPU hogs pushed to the
+    // bottom get to run again, and ages the contended times
+    fn boost(&mut self) {
+        self.contended = (self.contended.0 / 2, self.contended.1 / 2);
+
+        // a process never sits above the level of its priority, so each one moves up or goes
+        // around to the back of its own level
+        for level in 1..SCHED_LEVELS {
+            for _ in 0..self.levels[level].len() {
+                if let Some((tid, priority)) = self.levels[level].pop_front() {
+                    self.levels[priority].push_back((tid, priority));
+                }
+            }
+        }
+    }
+
+    // Counts `ran` towards the share of its class if the other class was kept waiting meanwhile
+    fn charge(&mut self, class: SchedClass, ran: u64) {
+        match class {
+            SchedClass::Mlfq if !self.fair.is_empty() => self.contended.0 += ran,
+            SchedClass::Fair { .. } if self.levels.iter().any(|queue| !queue.is_empty()) => {
+                self.contended.1 += ran
+            }
+            _ => {}
+        }
+    }
+
+    // Whether the fair class got less than SCHED_FAIR_SHARE of the contended time
+    fn fair_due(&self) -> bool {
+        let (mlfq, fair) = self.contended;
+        fair * 1000 < (mlfq + fair) * SCHED_FAIR_SHARE
+    }
+
+    fn pick_fair(&mut self) -> Option<Tid> {
+        let (vruntime, tid) = self.fair.pop_front()?;
+        self.min_vruntime = vruntime;
+        Some(tid)
+    }
+
+    fn enqueue(&mut self, tid: Tid, se: &mut SchedEntity, now: u64) {
+        match se.class {
+            SchedClass::RealTime {
+                period,
+                budget,
+                deadline,
+            } => {
+                // waking up in a later period starts a new one with a fresh budget
+                if now >= se.release + period {
+                    se.release = now;
+                    se.abs_deadline = now + deadline;
+                    se.budget_left = budget;
+                }
+                insert_sorted(&mut self.realtime, se.abs_deadline, tid);
+            }
+            SchedClass::Mlfq => {
+                self.catch_up(se);
+                self.levels[se.level].push_back((tid, se.priority.as_usize()));
+            }
+            SchedClass::Fair { .. } => {
+                se.vruntime = se.vruntime.max(self.min_vruntime);
+                insert_sorted(&mut self.fair, se.vruntime, tid);
+            }
+        }
+    }
+}
+
+impl Scheduler for Classes {
+    fn enqueue(&self, tid: Tid, se: &mut SchedEntity, now: u64) {
+        self.queues.lock().enqueue(tid, se, now);
+    }
+
+    fn dequeue(&self, tid: Tid) -> bool {
+        let mut queues = self.queues.lock();
+        let queues = &mut *queues;
+        for queue in [&mut queues.realtime, &mut queues.fair] {
+            if let Some(pos) = queue.iter().position(|(_, p)| *p == tid) {
+                queue.remove(pos);
+                return true;
+            }
+        }
+
+        for queue in queues.levels.iter_mut() {
+            if let Some(pos) = queue.iter().position(|(p, _)| *p == tid) {
+                queue.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn pick_next(&self, now: u64) -> Option<Tid> {
+        let mut queues = self.queues.lock();
+        queues.sync(Some(now));
+
+        if let Some((_, tid)) = queues.realtime.pop_front() {
+            return Some(tid);
+        }
+
+        if queues.fair_due()
+            && let Some(tid) = queues.pick_fair()
+        {
+            return Some(tid);
+        }
+
+        if let Some((tid, _)) = queues.levels.iter_mut().find_map(|queue| queue.pop_front()) {
+            return Some(tid);
+        }
+
+        queues.pick_fair()
+    }
+
+    // Fair-class threads go first, then MLFQ ones from the bottom level up; real-time threads
+    // only when there is nothing else, the one with the latest deadline first
+    fn steal(&self, can_move: &mut dyn FnMut(Tid) -> bool) -> Option<Tid> {
+        let mut queues = self.queues.lock();
+        if let Some(tid) = steal_sorted(&mut queues.fair, can_move) {
+            return Some(tid);
+        }
+
+        for queue in queues.levels.iter_mut().rev() {
+            if let Some(pos) = queue.iter().rposition(|(tid, _)| can_move(*tid)) {
+                return queue.remove(pos).map(|(tid, _)| tid);
+            }
+        }
+
+        steal_sorted(&mut queues.realtime, can_move)
+    }
+
+    // Real-time processes that overran their budget wait for their next period; CPU hogs that
+    // used up their MLFQ slice are demoted
+    fn on_tick(&self, tid: Tid, se: &mut SchedEntity, ran: u64, now: u64) -> Option<u64> {
+        let mut queues = self.queues.lock();
+        queues.charge(se.class, ran);
+        match se.class {
+            SchedClass::RealTime { period, .. } => {
+                se.budget_left = se.budget_left.saturating_sub(ran);
+                if se.budget_left == 0 {
+                    return Some(se.release + period);
+                }
+            }
+            SchedClass::Mlfq => {
+                queues.catch_up(se);
+                se.slice_used += ran;
+                if se.slice_used >= time_slice(se.level) {
+                    se.level = (se.level + 1).min(SCHED_LEVELS - 1);
+                    se.slice_used = 0;
+                }
+            }
+            SchedClass::Fair { weight } => se.vruntime += ran * SCHED_WEIGHT_UNIT / weight,
+        }
+
+        queues.enqueue(tid, se, now);
+        None
+    }
+
+    // MLFQ processes giving up the CPU before their slice is over are promoted
+    fn on_block(&self, se: &mut SchedEntity, ran: u64) {
+        let mut queues = self.queues.lock();
+        queues.charge(se.class, ran);
+        match se.class {
+            SchedClass::RealTime { .. } => se.budget_left = se.budget_left.saturating_sub(ran),
+            SchedClass::Mlfq => {
+                queues.catch_up(se);
+                se.slice_used += ran;
+                if se.slice_used < time_slice(se.level) {
+                    se.level = se.level.saturating_sub(1).max(se.priority.as_usize());
+                }
+                se.slice_used = 0;
+            }
+            SchedClass::Fair { weight } => se.vruntime += ran * SCHED_WEIGHT_UNIT / weight,
+        }
+    }
+
+    // Virtual runtime only compares within one run queue, so a fair-class thread keeps its lead or
+    // lag behind the last one picked when it moves
+    fn on_leave(&self, se: &mut SchedEntity) {
+        if let SchedClass::Fair { .. } = se.class {
+            se.vruntime = se.vruntime.saturating_sub(self.queues.lock().min_vruntime);
+        }
+    }
+
+    fn on_arrive(&self, se: &mut SchedEntity) {
+        if let SchedClass::Fair { .. } = se.class {
+            se.vruntime += self.queues.lock().min_vruntime;
+        }
+    }
+
+    fn preempt_at(&self, se: &SchedEntity, now: u64) -> Option<u64> {
+        match se.class {
+            SchedClass::RealTime { .. } => Some(now + se.budget_left),
+            _ => None,
+        }
+    }
+
+    // EDF meets every deadline as long as the densities add up to at most 100%;
+    // SCHED_RT_BANDWIDTH leaves some of the CPU to everybody else, and SCHED_RT_PROCESS_BANDWIDTH
+    // keeps a single process from reserving all of it
+    fn admit(
+        &self,
+        class: &SchedClass,
+        threads: usize,
+        others: &mut dyn Iterator<Item = SchedClass>,
+    ) -> Result<(), SchedError> {
+        let &SchedClass::RealTime {
+            budget, deadline, ..
+        } = class
+        else {
+            return Ok(());
+        };
+
+        let reserved: u64 = others
+            .map(|class| match class {
+                SchedClass::RealTime {
+                    budget, deadline, ..
+                } => rt_bandwidth(budget, deadline),
+                _ => 0,
+            })
+            .sum();
+        let own = threads as u64 * rt_bandwidth(budget, deadline);
+        if own > SCHED_RT_PROCESS_BANDWIDTH || reserved + own > SCHED_RT_BANDWIDTH {
+            return Err(SchedError::AdmissionDenied);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        let queues = self.queues.lock();
+        let mlfq: usize = queues.levels.iter().map(VecDeque::len).sum();
+        queues.realtime.len() + mlfq + queues.fair.len()
+    }
+}
+
+fn steal_sorted(
+    queue: &mut VecDeque<(u64, Tid)>,
+    can_move: &mut dyn FnMut(Tid) -> bool,
+) -> Option<Tid> {
+    let pos = queue.iter().rposition(|(_, tid)| can_move(*tid))?;
+    queue.remove(pos).map(|(_, tid)| tid)
+}
+
+fn insert_sorted(queue: &mut VecDeque<(u64, Tid)>, key: u64, tid: Tid) {
+    let pos = queue.partition_point(|(k, _)| *k <= key);
+    queue.insert(pos, (key, tid));
+}
+
+// Density of a real-time reservation in permille, rounded up
+fn rt_bandwidth(budget: u64, deadline: u64) -> u64 {
+    (budget * 1000).div_ceil(deadline)
+}
+
+fn time_slice(level: usize) -> u64 {
+    us_to_ticks(SCHED_SLICE_US << level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::Priority;
+
+    const A: Tid = Tid::new(10, 0);
+    const B: Tid = Tid::new(11, 0);
+    const C: Tid = Tid::new(12, 0);
+
+    fn realtime(budget: u64, deadline: u64) -> SchedEntity {
+        let mut se = SchedEntity::new(Priority::NORMAL);
+        let class = SchedClass::RealTime {
+            period: deadline,
+            budget,
+            deadline,
+        };
+        se.set_class(class, 0);
+        se
+    }
+
+    fn fair(vruntime: u64) -> SchedEntity {
+        let mut se = SchedEntity::new(Priority::NORMAL);
+        se.set_class(SchedClass::Fair { weight: 1024 }, 0);
+        se.vruntime = vruntime;
+        se
+    }
+
+    // a time at which no boost is due, whatever the tests before did
+    fn now() -> u64 {
+        BOOSTS.lock().last
+    }
+
+    fn picks(classes: &Classes) -> Vec<Tid> {
+        core::iter::from_fn(|| classes.pick_next(now())).collect()
+    }
+
+    #[test]
+    fn realtime_by_deadline_before_the_rest() {
+        let classes = Classes::new();
+        classes.enqueue(A, &mut SchedEntity::new(Priority::new(0)), now());
+        classes.enqueue(B, &mut realtime(10, 300), 0);
+        classes.enqueue(C, &mut realtime(10, 200), 0);
+        assert_eq!(picks(&classes), [C, B, A]);
+    }
+
+    #[test]
+    fn realtime_waits_for_its_next_period_once_over_budget() {
+        let classes = Classes::new();
+        let mut se = realtime(10, 100);
+        assert_eq!(classes.on_tick(A, &mut se, 4, 4), None);
+        assert_eq!(classes.pick_next(4), Some(A));
+        assert_eq!(classes.on_tick(A, &mut se, 6, 10), Some(100));
+        assert!(classes.is_empty());
+
+        classes.on_wake(A, &mut se, 100);
+        assert_eq!((se.budget_left, se.abs_deadline), (10, 200));
+    }
+
+    #[test]
+    fn mlfq_demotes_hogs_and_promotes_sleepers() {
+        let classes = Classes::new();
+        let mut hog = SchedEntity::new(Priority::NORMAL);
+        let mut other = SchedEntity::new(Priority::NORMAL);
+        classes.on_tick(A, &mut hog, time_slice(1), now());
+        classes.enqueue(B, &mut other, now());
+        assert_eq!(hog.level, 2);
+        assert_eq!(picks(&classes), [B, A]);
+
+        classes.on_block(&mut hog, 1);
+        assert_eq!(hog.level, 1);
+        classes.on_block(&mut hog, 1);
+        assert_eq!(hog.level, 1);
+    }
+
+    #[test]
+    fn boost_brings_demoted_threads_back_up() {
+        let classes = Classes::new();
+        let mut hog = SchedEntity::new(Priority::NORMAL);
+        let mut low = SchedEntity::new(Priority::new(2));
+        let mut sleeper = SchedEntity::new(Priority::NORMAL);
+        classes.enqueue(B, &mut low, now());
+        classes.on_tick(A, &mut hog, time_slice(1), now());
+        classes.on_tick(C, &mut sleeper, time_slice(1), now());
+        classes.dequeue(C);
+
+        let later = now() + us_to_ticks(SCHED_BOOST_PERIOD_US);
+        assert_eq!(classes.pick_next(later), Some(A));
+        assert_eq!(classes.pick_next(later), Some(B));
+
+        // not queued during the boost, so it catches up when it is
+        assert_eq!(sleeper.level, 2);
+        classes.enqueue(C, &mut sleeper, later);
+        assert_eq!(sleeper.level, 1);
+    }
+
+    #[test]
+    fn fair_by_vruntime_and_sleepers_catch_up_to_the_minimum() {
+        let classes = Classes::new();
+        classes.enqueue(A, &mut fair(300), now());
+        classes.enqueue(B, &mut fair(100), now());
+        classes.enqueue(C, &mut fair(200), now());
+        assert_eq!(picks(&classes), [B, C, A]);
+
+        let mut sleeper = fair(0);
+        classes.enqueue(B, &mut sleeper, now());
+        assert_eq!(sleeper.vruntime, 300);
+    }
+
+    #[test]
+    fn fair_class_gets_its_share_of_contended_time() {
+        let classes = Classes::new();
+        let mut mlfq = SchedEntity::new(Priority::NORMAL);
+        classes.enqueue(A, &mut mlfq, now());
+        classes.enqueue(B, &mut fair(0), now());
+        assert_eq!(classes.pick_next(now()), Some(A));
+
+        classes.on_tick(A, &mut mlfq, 1000, now());
+        assert_eq!(classes.pick_next(now()), Some(B));
+    }
+
+    #[test]
+    fn steal_takes_what_would_run_last() {
+        let classes = Classes::new();
+        let mut demoted = SchedEntity::new(Priority::NORMAL);
+        classes.enqueue(A, &mut realtime(10, 300), 0);
+        classes.enqueue(B, &mut realtime(10, 200), 0);
+        classes.enqueue(C, &mut SchedEntity::new(Priority::NORMAL), now());
+        classes.on_tick(Tid::new(13, 0), &mut demoted, time_slice(1), now());
+        classes.enqueue(Tid::new(14, 0), &mut fair(0), now());
+        classes.enqueue(Tid::new(15, 0), &mut fair(10), now());
+
+        let stolen: Vec<Tid> = core::iter::from_fn(|| classes.steal(&mut |tid| tid != B)).collect();
+        assert_eq!(
+            stolen,
+            [Tid::new(15, 0), Tid::new(14, 0), Tid::new(13, 0), C, A]
+        );
+        assert_eq!(classes.len(), 1);
+    }
+
+    #[test]
+    fn vruntime_keeps_its_lag_across_run_queues() {
+        let from = Classes::new();
+        let to = Classes::new();
+        from.enqueue(A, &mut fair(1000), now());
+        to.enqueue(A, &mut fair(10), now());
+        from.pick_next(now());
+        to.pick_next(now());
+
+        let mut se = fair(1200);
+        from.on_leave(&mut se);
+        to.on_arrive(&mut se);
+        assert_eq!(se.vruntime, 210);
+    }
+
+    #[test]
+    fn admission_caps_realtime_bandwidth() {
+        let classes = Classes::new();
+        let class = SchedClass::RealTime {
+            period: 100,
+            budget: 10,
+            deadline: 100,
+        };
+        let admit = |threads, others: &[SchedClass]| {
+            classes.admit(&class, threads, &mut others.iter().copied())
+        };
+
+        assert!(admit(3, &[]).is_ok());
+        assert!(matches!(admit(4, &[]), Err(SchedError::AdmissionDenied)));
+        assert!(admit(3, &[class; 6]).is_ok());
+        assert!(admit(3, &[class; 7]).is_err());
+        assert!(admit(3, &[SchedClass::Mlfq; 20]).is_ok());
+        assert!(
+            classes
+                .admit(&SchedClass::Mlfq, 100, &mut [class; 20].into_iter())
+                .is_ok()
+        );
+    }
+}
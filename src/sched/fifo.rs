@@ -0,0 +1,67 @@
+use alloc::collections::VecDeque;
+
+use super::{SchedClass, SchedEntity, SchedError, Scheduler};
+use crate::{process::Tid, sync::SpinLock};
+
+// Round-robin over all runnable processes, ignoring priorities and classes
+pub struct Fifo {
+    queue: SpinLock<VecDeque<Tid>>,
+}
+
+impl Fifo {
+    pub const fn new() -> Self {
+        Fifo {
+            queue: SpinLock::new(VecDeque::new()),
+        }
+    }
+}
+
+impl Scheduler for Fifo {
+    fn enqueue(&self, tid: Tid, _se: &mut SchedEntity, _now: u64) {
+        self.queue.lock().push_back(tid);
+    }
+
+    fn dequeue(&self, tid: Tid) -> bool {
+        let mut queue = self.queue.lock();
+        if let Some(pos) = queue.iter().position(|p| *p == tid) {
+            queue.remove(pos);
+            return true;
+        }
+        false
+    }
+
+    fn pick_next(&self, _now: u64) -> Option<Tid> {
+        self.queue.lock().pop_front()
+    }
+
+    fn steal(&self, can_move: &mut dyn FnMut(Tid) -> bool) -> Option<Tid> {
+        let mut queue = self.queue.lock();
+        let pos = queue.iter().rposition(|tid| can_move(*tid))?;
+        queue.remove(pos)
+    }
+
+    fn on_tick(&self, tid: Tid, se: &mut SchedEntity, _ran: u64, now: u64) -> Option<u64> {
+        self.enqueue(tid, se, now);
+        None
+    }
+
+    fn on_block(&self, _se: &mut SchedEntity, _ran: u64) {}
+
+    fn admit(
+        &self,
+        class: &SchedClass,
+        _threads: usize,
+        _others: &mut dyn Iterator<Item = SchedClass>,
+    ) -> Result<(), SchedError> {
+        match class {
+            SchedClass::RealTime { .. } | SchedClass::Fair { .. } => {
+                Err(SchedError::AdmissionDenied)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+}
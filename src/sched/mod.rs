@@ -0,0 +1,230 @@
+#[cfg(not(feature = "sched-fifo"))]
+mod classes;
+#[cfg(feature = "sched-fifo")]
+mod fifo;
+
+use core::fmt;
+
+use crate::{
+    constants::{MAX_HARTS, SCHED_LEVELS},
+    process::Tid,
+};
+
+// Real-time processes run before everything else, earliest deadline first. What they leave goes
+// to MLFQ processes, except for SCHED_FAIR_SHARE of it that the fair class gets while both have
+// something to run; fair-class processes split their time in proportion to their weights.
+// Round-robin ignores the class, so it refuses the real-time and fair classes it could not honour.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchedClass {
+    // at most `budget` ticks of CPU time every `period` ticks, within `deadline` ticks of the
+    // start of the period
+    RealTime {
+        period: u64,
+        budget: u64,
+        deadline: u64,
+    },
+    Mlfq,
+    Fair {
+        weight: u64,
+    },
+}
+
+impl SchedClass {
+    pub fn validate(&self) -> Result<(), SchedError> {
+        let valid = match *self {
+            SchedClass::RealTime {
+                period,
+                budget,
+                deadline,
+            } => budget > 0 && budget <= deadline && deadline <= period,
+            SchedClass::Mlfq => true,
+            SchedClass::Fair { weight } => weight > 0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(SchedError::InvalidParameters)
+        }
+    }
+}
+
+impl fmt::Display for SchedClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchedClass::RealTime { .. } => write!(f, "edf"),
+            SchedClass::Mlfq => write!(f, "mlfq"),
+            SchedClass::Fair { weight } => write!(f, "fair/{weight}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SchedError {
+    NoSuchProcess,
+    InvalidParameters,
+    // the policy cannot guarantee the reservation on top of the existing ones
+    AdmissionDenied,
+    // no free slot in the thread table
+    TableFull,
+    // no memory left for the kernel stack of a new thread
+    OutOfMemory,
+}
+
+// 0 is the highest priority, SCHED_LEVELS - 1 the lowest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Priority(usize);
+
+impl Priority {
+    pub const NORMAL: Self = Priority(1);
+    pub const LOWEST: Self = Priority(SCHED_LEVELS - 1);
+
+    pub const fn new(level: usize) -> Self {
+        if level < SCHED_LEVELS {
+            Priority(level)
+        } else {
+            Self::LOWEST
+        }
+    }
+
+    pub const fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+// Per-thread scheduling state; it lives in the thread table and is handed to the policy.
+// Round-robin only needs part of it.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "sched-fifo", allow(dead_code))]
+pub struct SchedEntity {
+    pub priority: Priority,
+    pub class: SchedClass,
+    // current MLFQ level, never above `priority`
+    pub level: usize,
+    pub slice_used: u64,
+    // priority boost the level was last brought up to date with
+    pub epoch: u64,
+    // running time scaled by SCHED_WEIGHT_UNIT / weight, for the fair class
+    pub vruntime: u64,
+    // current period of a real-time process: its start, absolute deadline and what is left of
+    // the budget
+    pub release: u64,
+    pub abs_deadline: u64,
+    pub budget_left: u64,
+}
+
+impl SchedEntity {
+    pub const fn new(priority: Priority) -> Self {
+        SchedEntity {
+            priority,
+            class: SchedClass::Mlfq,
+            level: priority.as_usize(),
+            slice_used: 0,
+            epoch: 0,
+            vruntime: 0,
+            release: 0,
+            abs_deadline: 0,
+            budget_left: 0,
+        }
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+        self.level = priority.as_usize();
+        self.slice_used = 0;
+    }
+
+    // A real-time process starts its first period right away
+    pub fn set_class(&mut self, class: SchedClass, now: u64) {
+        self.class = class;
+        self.level = self.priority.as_usize();
+        self.slice_used = 0;
+        if let SchedClass::RealTime {
+            budget, deadline, ..
+        } = class
+        {
+            self.release = now;
+            self.abs_deadline = now + deadline;
+            self.budget_left = budget;
+        }
+    }
+}
+
+// A scheduling policy for the run queue of one hart. The process manager tells it which threads
+// are runnable and what they ran; the policy keeps the run queue and decides who runs next. The
+// idle threads are never queued.
+pub trait Scheduler: Sync {
+    // Makes a runnable thread eligible to run
+    fn enqueue(&self, tid: Tid, se: &mut SchedEntity, now: u64);
+
+    // Takes a thread out of the run queue, returning whether it was queued
+    fn dequeue(&self, tid: Tid) -> bool;
+
+    // Takes the thread to run next out of the run queue
+    fn pick_next(&self, now: u64) -> Option<Tid>;
+
+    // Takes a thread out of the run queue for another hart, the one that would run last among
+    // those `can_move` accepts
+    fn steal(&self, can_move: &mut dyn FnMut(Tid) -> bool) -> Option<Tid>;
+
+    // The running thread is switched out while still runnable after running for `ran` ticks.
+    // Returns when it may run again if it has to wait, otherwise it is queued again.
+    fn on_tick(&self, tid: Tid, se: &mut SchedEntity, ran: u64, now: u64) -> Option<u64>;
+
+    // The running thread blocked, went to sleep or exited after running for `ran` ticks
+    fn on_block(&self, se: &mut SchedEntity, ran: u64);
+
+    // A blocked, sleeping or throttled thread became runnable again
+    fn on_wake(&self, tid: Tid, se: &mut SchedEntity, now: u64) {
+        self.enqueue(tid, se, now);
+    }
+
+    // A thread is about to move from this run queue, or from this hart if it is not queued, to
+    // another hart, which `on_arrive` is called for next
+    fn on_leave(&self, _se: &mut SchedEntity) {}
+
+    fn on_arrive(&self, _se: &mut SchedEntity) {}
+
+    // When the timer has to interrupt a thread dispatched at `now`, if the policy needs it
+    // earlier than the next tick
+    fn preempt_at(&self, _se: &SchedEntity, _now: u64) -> Option<u64> {
+        None
+    }
+
+    // Decides whether `class` can be granted to `threads` threads of a process, next to the
+    // classes of the live threads of all other processes
+    fn admit(
+        &self,
+        _class: &SchedClass,
+        _threads: usize,
+        _others: &mut dyn Iterator<Item = SchedClass>,
+    ) -> Result<(), SchedError> {
+        Ok(())
+    }
+
+    // Number of threads in the run queue
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// The policy is picked at build time: the `sched-fifo` feature swaps the class-based scheduler
+// for plain round-robin. A new policy is a module implementing `Scheduler` plus a feature here.
+#[cfg(not(feature = "sched-fifo"))]
+static RUN_QUEUES: [classes::Classes; MAX_HARTS] = [const { classes::Classes::new() }; MAX_HARTS];
+#[cfg(feature = "sched-fifo")]
+static RUN_QUEUES: [fifo::Fifo; MAX_HARTS] = [const { fifo::Fifo::new() }; MAX_HARTS];
+
+// An instance of the policy per hart. They are only ever touched under the kernel lock, see
+// `sync::lock_kernel`, so the one SpinLock each keeps its state behind is never contended; the
+// contention moved to the kernel lock rather than going away.
+pub const fn run_queues() -> [&'static dyn Scheduler; MAX_HARTS] {
+    let mut queues: [&'static dyn Scheduler; MAX_HARTS] = [&RUN_QUEUES[0]; MAX_HARTS];
+    let mut hart = 1;
+    while hart < MAX_HARTS {
+        queues[hart] = &RUN_QUEUES[hart];
+        hart += 1;
+    }
+    queues
+}
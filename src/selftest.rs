@@ -0,0 +1,73 @@
+use core::{fmt::Write, time::Duration};
+
+use crate::{
+    print, println,
+    process::{PM, Pid, spawn, with_kernel},
+    sbi::sbi_call,
+    sync::{Mutex, Semaphore},
+    timer::sleep,
+};
+
+const SBI_EID_SRST: usize = 0x53525354;
+const SBI_FID_SYSTEM_RESET: usize = 0;
+const SBI_RESET_SHUTDOWN: usize = 0;
+
+const ADDERS: usize = 4;
+
+// what the adders were given so far, and one unit from each adder that is done
+static SUM: Mutex<usize> = Mutex::new(0);
+static ADDED: Semaphore = Semaphore::new(0);
+
+// One closure started as several kernel threads, each with a number of its own to add to SUM.
+// It sleeps between reading and writing the sum, so the others have to wait on the mutex.
+fn adder(n: usize) {
+    with_kernel(|| {
+        let mut sum = SUM.lock();
+        let seen = *sum;
+        sleep(Duration::from_millis(n as u64));
+        *sum = seen + n;
+        println!("selftest: kernel thread {} added {n}", PM.current_pid());
+        drop(sum);
+        ADDED.release();
+    });
+}
+
+// Checks of what only kernel code can reach, run at boot by kernels built with the `selftest`
+// feature. They wait for `user`, the checks of the syscalls in user/src/bin/selftest.rs, and
+// power the machine off after it.
+pub fn start(user: Pid) {
+    for n in 1..=ADDERS {
+        spawn("adder", move || adder(n)).expect("out of memory");
+    }
+    spawn("selftest", move || {
+        with_kernel(|| {
+            for _ in 0..ADDERS {
+                ADDED.acquire();
+            }
+            let sum = *SUM.lock();
+            let expected = ADDERS * (ADDERS + 1) / 2;
+            if sum == expected {
+                println!("selftest: spawn ok");
+            } else {
+                println!("selftest: spawn FAILED: the adders got {sum} instead of {expected}");
+            }
+
+            while PM.get(user).is_some() {
+                sleep(Duration::from_millis(10));
+            }
+            println!("selftest: done, powering off");
+            let ret = sbi_call(
+                SBI_RESET_SHUTDOWN,
+                0,
+                0,
+                0,
+                0,
+                0,
+                SBI_FID_SYSTEM_RESET,
+                SBI_EID_SRST,
+            );
+            println!("selftest: power off failed: {ret:?}");
+        });
+    })
+    .expect("out of memory");
+}
@@ -0,0 +1,223 @@
+use alloc::vec::Vec;
+
+use crate::{
+    constants::{
+        PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W, USER_SHM_BASE, USER_STACK_PAGES, USER_STACK_TOP,
+    },
+    memory::{alloc_user_page, free_page, map_page, unmap_page},
+    process::{PM, Pid},
+    sync::SpinLock,
+    utils::{Addr, PhysAddr, VirtAddr},
+};
+
+#[derive(Debug)]
+pub enum ShmError {
+    // no pages, or not the size of the segment already under the key
+    InvalidSize,
+    // more shared memory than the process is allowed
+    Limit,
+    // no memory left, or no room between the heap and the stack
+    OutOfMemory,
+    NotAttached,
+}
+
+// Pages shared by every process that attaches the same key, each at an address of its own. The
+// pages are freed once the last of them detaches.
+struct Segment {
+    key: usize,
+    pages: Vec<PhysAddr>,
+    users: Vec<(Pid, usize)>,
+}
+
+static SEGMENTS: SpinLock<Vec<Segment>> = SpinLock::new(Vec::new());
+
+// Pages of shared memory `pid` has attached, counting a segment once per attachment
+fn attached(segments: &[Segment], pid: Pid) -> usize {
+    segments
+        .iter()
+        .map(|seg| seg.pages.len() * seg.users.iter().filter(|(p, _)| *p == pid).count())
+        .sum()
+}
+
+// The lowest address with `pages` unused pages above it in the shared memory area of `pid`
+fn find_room(segments: &[Segment], pid: Pid, pages: usize) -> Option<usize> {
+    let mut taken: Vec<(usize, usize)> = segments
+        .iter()
+        .flat_map(|seg| {
+            seg.users
+                .iter()
+                .filter(|(p, _)| *p == pid)
+                .map(|(_, addr)| (*addr, *addr + seg.pages.len() * PAGE_SIZE))
+        })
+        .collect();
+    taken.sort_unstable();
+
+    let end = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+    let size = pages.checked_mul(PAGE_SIZE)?;
+    let mut addr = USER_SHM_BASE;
+    for (start, stop) in taken {
+        if start - addr >= size {
+            break;
+        }
+        addr = stop;
+    }
+    (end - addr >= size).then_some(addr)
+}
+
+// Attaches the segment under `key` to the current process, creating it with `pages` zeroed pages
+// if there is none yet, and returns where it is mapped
+pub fn attach(key: usize, pages: usize) -> Result<usize, ShmError> {
+    let pid = PM.current_pid();
+    let page_table = PM.page_table(pid);
+    let limit = PM.limits(pid).map_or(0, |limits| limits.shm);
+
+    let mut segments = SEGMENTS.lock();
+    if pages == 0 {
+        return Err(ShmError::InvalidSize);
+    }
+    if attached(&segments, pid) + pages > limit {
+        return Err(ShmError::Limit);
+    }
+    let addr = find_room(&segments, pid, pages).ok_or(ShmError::OutOfMemory)?;
+
+    let index = match segments.iter().position(|seg| seg.key == key) {
+        Some(index) if segments[index].pages.len() != pages => {
+            return Err(ShmError::InvalidSize);
+        }
+        Some(index) => index,
+        None => {
+            let mut seg = Segment {
+                key,
+                pages: Vec::with_capacity(pages),
+                users: Vec::new(),
+            };
+            for _ in 0..pages {
+                let Some(page) = alloc_user_page() else {
+                    seg.pages.into_iter().for_each(free_page);
+                    return Err(ShmError::OutOfMemory);
+                };
+                seg.pages.push(page);
+            }
+            segments.push(seg);
+            segments.len() - 1
+        }
+    };
+
+    segments[index].users.push((pid, addr));
+    for (i, page) in segments[index].pages.iter().enumerate() {
+        let vaddr = VirtAddr::from_usize(addr + i * PAGE_SIZE);
+        if !map_page(page_table, vaddr, *page, PAGE_U | PAGE_R | PAGE_W) {
+            remove_user(&mut segments, index, pid, addr);
+            return Err(ShmError::OutOfMemory);
+        }
+    }
+    Ok(addr)
+}
+
+// Detaches the segment the current process attached at `addr`
+pub fn detach(addr: usize) -> Result<(), ShmError> {
+    let pid = PM.current_pid();
+    let mut segments = SEGMENTS.lock();
+    let index = segments
+        .iter()
+        .position(|seg| seg.users.contains(&(pid, addr)))
+        .ok_or(ShmError::NotAttached)?;
+    remove_user(&mut segments, index, pid, addr);
+    Ok(())
+}
+
+// Detaches every segment of `pid`, for a process that ends or gets a new address space. Its
+// threads still running elsewhere lose access right away.
+pub fn detach_all(pid: Pid) {
+    let mut segments = SEGMENTS.lock();
+    while let Some((index, addr)) = segments.iter().enumerate().find_map(|(index, seg)| {
+        seg.users
+            .iter()
+            .find(|(p, _)| *p == pid)
+            .map(|(_, addr)| (index, *addr))
+    }) {
+        remove_user(&mut segments, index, pid, addr);
+    }
+}
+
+fn remove_user(segments: &mut Vec<Segment>, index: usize, pid: Pid, addr: usize) {
+    let page_table = PM.page_table(pid);
+    let seg = &mut segments[index];
+    for i in 0..seg.pages.len() {
+        unmap_page(page_table, VirtAddr::from_usize(addr + i * PAGE_SIZE));
+    }
+    PM.flush_tlb(pid, addr, seg.pages.len() * PAGE_SIZE);
+
+    seg.users.retain(|user| *user != (pid, addr));
+    if seg.users.is_empty() {
+        segments
+            .swap_remove(index)
+            .pages
+            .into_iter()
+            .for_each(free_page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Pid = Pid::new(1, 0);
+    const B: Pid = Pid::new(2, 0);
+
+    fn segment(pages: usize, users: &[(Pid, usize)]) -> Segment {
+        Segment {
+            key: 0,
+            pages: alloc::vec![PhysAddr::from_usize(0); pages],
+            users: users.to_vec(),
+        }
+    }
+
+    fn page(n: usize) -> usize {
+        USER_SHM_BASE + n * PAGE_SIZE
+    }
+
+    #[test]
+    fn room_at_the_bottom_first() {
+        assert_eq!(find_room(&[], A, 1), Some(page(0)));
+
+        let segments = [segment(2, &[(A, page(0))])];
+        assert_eq!(find_room(&segments, A, 1), Some(page(2)));
+    }
+
+    #[test]
+    fn room_in_a_hole_that_fits() {
+        let segments = [segment(1, &[(A, page(3))]), segment(1, &[(A, page(0))])];
+        assert_eq!(find_room(&segments, A, 2), Some(page(1)));
+        assert_eq!(find_room(&segments, A, 3), Some(page(4)));
+    }
+
+    #[test]
+    fn room_ignores_other_processes() {
+        let segments = [segment(2, &[(B, page(0)), (A, page(2))])];
+        assert_eq!(find_room(&segments, B, 1), Some(page(2)));
+        assert_eq!(find_room(&segments, A, 2), Some(page(0)));
+        assert_eq!(find_room(&segments, A, 3), Some(page(4)));
+    }
+
+    #[test]
+    fn no_room_past_the_stack() {
+        let area = (USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE - USER_SHM_BASE) / PAGE_SIZE;
+        assert_eq!(find_room(&[], A, area), Some(page(0)));
+        assert_eq!(find_room(&[], A, area + 1), None);
+        assert_eq!(find_room(&[], A, usize::MAX), None);
+
+        let segments = [segment(1, &[(A, page(area / 2))])];
+        assert_eq!(find_room(&segments, A, area / 2 + 1), None);
+    }
+
+    #[test]
+    fn attached_counts_every_attachment() {
+        let segments = [
+            segment(2, &[(A, page(0)), (B, page(0)), (A, page(2))]),
+            segment(3, &[(B, page(2))]),
+        ];
+        assert_eq!(attached(&segments, A), 4);
+        assert_eq!(attached(&segments, B), 5);
+    }
+}
@@ -0,0 +1,260 @@
+use core::{
+    arch::asm,
+    cell::{Cell, UnsafeCell},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    constants::{MAX_HARTS, SSTATUS_SIE},
+    hart,
+    process::PM,
+    read_csr,
+    timer::get_time,
+    utils::{irq_disable, irq_enable},
+    wait_queue::WaitQueue,
+};
+
+// How many spin lock guards a hart holds, and whether interrupts were enabled before the first
+// one. Interrupts come back on when the last guard goes, in whatever order the guards are dropped.
+struct IrqNesting {
+    depth: Cell<usize>,
+    enabled: Cell<bool>,
+}
+
+// each hart only touches its own, with interrupts disabled
+unsafe impl Sync for IrqNesting {}
+
+static IRQ_NESTING: [IrqNesting; MAX_HARTS] = [const {
+    IrqNesting {
+        depth: Cell::new(0),
+        enabled: Cell::new(false),
+    }
+}; MAX_HARTS];
+
+fn push_irq_off() {
+    let enabled = read_csr!("sstatus") & SSTATUS_SIE != 0;
+    irq_disable();
+    let nesting = &IRQ_NESTING[hart::id()];
+    if nesting.depth.get() == 0 {
+        nesting.enabled.set(enabled);
+    }
+    nesting.depth.set(nesting.depth.get() + 1);
+}
+
+fn pop_irq_off() {
+    let nesting = &IRQ_NESTING[hart::id()];
+    let depth = nesting.depth.get() - 1;
+    nesting.depth.set(depth);
+    if depth == 0 && nesting.enabled.get() {
+        irq_enable();
+    }
+}
+
+// The target has no A extension, but the harts QEMU provides do
+fn swap_locked(locked: &AtomicU32, value: u32) -> u32 {
+    let old: u32;
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +a",
+            "amoswap.w.aq {old}, {value}, ({addr})",
+            ".option pop",
+            old = out(reg) old,
+            value = in(reg) value,
+            addr = in(reg) locked.as_ptr(),
+        );
+    }
+    old
+}
+
+// Lets one hart at a time into the kernel: taken on every way in and dropped on the way back to
+// user space or into wfi. It is held across context switches, so whichever thread runs next
+// releases what the previous one took. It holds the id of the hart inside plus one.
+//
+// The run queues are per hart, but every trap serializes here, so they only buy affinity and
+// cache locality: scheduling itself never runs on two harts at once, and this lock, not the run
+// queues, is what busy harts contend for. Taking enqueue, pick and steal out from under it needs
+// the thread table to stop relying on it first. KERNEL_LOCK_WAIT shows what that costs.
+static KERNEL_LOCK: AtomicU32 = AtomicU32::new(0);
+
+// ticks each hart spent spinning for the kernel lock
+static KERNEL_LOCK_WAIT: [SpinLock<u64>; MAX_HARTS] = [const { SpinLock::new(0) }; MAX_HARTS];
+
+pub fn lock_kernel() {
+    let owner = hart::id() as u32 + 1;
+    if swap_locked(&KERNEL_LOCK, owner) == 0 {
+        return;
+    }
+
+    let start = get_time();
+    while swap_locked(&KERNEL_LOCK, owner) != 0 {
+        core::hint::spin_loop();
+    }
+    *KERNEL_LOCK_WAIT[hart::id()].lock() += get_time() - start;
+}
+
+pub fn kernel_lock_wait(hart: usize) -> u64 {
+    *KERNEL_LOCK_WAIT[hart].lock()
+}
+
+// False on a hart running a kernel thread outside the kernel, see `process::spawn`
+pub fn holds_kernel_lock() -> bool {
+    KERNEL_LOCK.load(Ordering::Relaxed) == hart::id() as u32 + 1
+}
+
+pub fn unlock_kernel() {
+    KERNEL_LOCK.store(0, Ordering::Release);
+}
+
+// Busy-waits for the lock with interrupts disabled, so that the holder is never interrupted by
+// code wanting the same lock. Guards must not be held across a context switch.
+pub struct SpinLock<T> {
+    locked: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        push_irq_off();
+        while swap_locked(&self.locked, 1) != 0 {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(0, Ordering::Release);
+        pop_irq_off();
+    }
+}
+
+// A lock whose waiters sleep instead of spinning, for critical sections that may block. Process
+// context only, and a holder that gets killed never releases it. Only the self-tests use it and
+// `Semaphore` so far.
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+pub struct Mutex<T> {
+    locked: SpinLock<bool>,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: SpinLock::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            {
+                let mut locked = self.locked.lock();
+                if !*locked {
+                    *locked = true;
+                    return MutexGuard { mutex: self };
+                }
+                // queued before the lock is released, so the wake-up from unlock cannot be missed
+                self.waiters.prepare_wait();
+            }
+            PM.switch();
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.locked.lock() = false;
+        self.mutex.waiters.wake_one();
+    }
+}
+
+// Counts units of something; taking one when there are none sleeps until one is given back.
+// Process context only.
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+pub struct Semaphore {
+    count: SpinLock<usize>,
+    waiters: WaitQueue,
+}
+
+#[cfg_attr(not(feature = "selftest"), allow(dead_code))]
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: SpinLock::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) {
+        loop {
+            {
+                let mut count = self.count.lock();
+                if *count > 0 {
+                    *count -= 1;
+                    return;
+                }
+                self.waiters.prepare_wait();
+            }
+            PM.switch();
+        }
+    }
+
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+}
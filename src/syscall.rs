@@ -0,0 +1,565 @@
+use alloc::{string::String, vec, vec::Vec};
+use core::{fmt::Write, time::Duration};
+
+use abi::{
+    ARGV_MAX, E2BIG, EAGAIN, EBUSY, EDEADLK, EFAULT, EINVAL, EIO, ENOENT, ENOEXEC, ENOMEM, ENOSYS,
+    EPERM, ERANGE, ESRCH, ETIMEDOUT, FUTEX_WAIT, FUTEX_WAKE, MESSAGE_WORDS, RLIM_INFINITY,
+    RLIMIT_CHILDREN, RLIMIT_CPU, RLIMIT_IPC_QUEUE, RLIMIT_PAGES, RLIMIT_SHM, SCHED_EDF, SCHED_FAIR,
+    SCHED_MLFQ, SRC_ANY, SYS_BRK, SYS_CHECKPOINT, SYS_EXEC, SYS_EXIT, SYS_FUTEX, SYS_GETPID,
+    SYS_GETRLIMIT, SYS_RECV, SYS_RESTORE, SYS_SBRK, SYS_SCHED_SET, SYS_SEND, SYS_SET_AFFINITY,
+    SYS_SETRLIMIT, SYS_SHM_ATTACH, SYS_SHM_DETACH, SYS_SLEEP, SYS_SPAWN, SYS_THREAD_CREATE,
+    SYS_THREAD_EXIT, SYS_THREAD_JOIN, SYS_WATCHDOG, SYS_WRITE, SYS_YIELD, SYSCALL_COUNT,
+    TIMEBASE_FREQ, WATCHDOG_KILL, WATCHDOG_REPORT, WATCHDOG_RESTART,
+};
+
+use crate::{
+    checkpoint::{self, CheckpointError},
+    constants::{PAGE_SIZE, PROC_THREADS_MAX},
+    elf::ElfError,
+    futex::{self, FutexError},
+    hart::Affinity,
+    ipc::{Ipc, IpcError, Message, Src},
+    memory::{copy_from_user, copy_to_user},
+    print,
+    process::{PM, Pid, Resource, ThreadError, Tid},
+    programs::{self, Program},
+    sched::{Priority, SchedClass, SchedError},
+    shm::{self, ShmError},
+    timer::{sleep, us_to_ticks},
+    trap_handler::TrapFrame,
+    utils::{Addr, VirtAddr},
+    watchdog::Action,
+};
+
+// bytes SYS_WRITE copies in at a time
+const WRITE_CHUNK: usize = 256;
+
+type SysResult = Result<usize, isize>;
+type Handler = fn(&[usize; 6]) -> SysResult;
+
+const SYSCALL_TABLE: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut table: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[SYS_EXIT] = Some(sys_exit);
+    table[SYS_YIELD] = Some(sys_yield);
+    table[SYS_GETPID] = Some(sys_getpid);
+    table[SYS_SLEEP] = Some(sys_sleep);
+    table[SYS_WRITE] = Some(sys_write);
+    table[SYS_SEND] = Some(sys_send);
+    table[SYS_RECV] = Some(sys_recv);
+    table[SYS_BRK] = Some(sys_brk);
+    table[SYS_SPAWN] = Some(sys_spawn);
+    table[SYS_EXEC] = Some(sys_exec);
+    table[SYS_SCHED_SET] = Some(sys_sched_set);
+    table[SYS_THREAD_CREATE] = Some(sys_thread_create);
+    table[SYS_THREAD_EXIT] = Some(sys_thread_exit);
+    table[SYS_THREAD_JOIN] = Some(sys_thread_join);
+    table[SYS_SBRK] = Some(sys_sbrk);
+    table[SYS_GETRLIMIT] = Some(sys_getrlimit);
+    table[SYS_SETRLIMIT] = Some(sys_setrlimit);
+    table[SYS_WATCHDOG] = Some(sys_watchdog);
+    table[SYS_CHECKPOINT] = Some(sys_checkpoint);
+    table[SYS_RESTORE] = Some(sys_restore);
+    table[SYS_FUTEX] = Some(sys_futex);
+    table[SYS_SET_AFFINITY] = Some(sys_set_affinity);
+    table[SYS_SHM_ATTACH] = Some(sys_shm_attach);
+    table[SYS_SHM_DETACH] = Some(sys_shm_detach);
+    table
+};
+
+// Number in a7, arguments in a0-a5, result or negated errno in a0. sepc stays on the ecall
+// while the call is in progress, so that a checkpoint taken meanwhile makes the call again.
+pub fn handle_syscall(frame: &mut TrapFrame) {
+    let args = [frame.a0, frame.a1, frame.a2, frame.a3, frame.a4, frame.a5];
+    let handler = SYSCALL_TABLE.get(frame.a7).copied().flatten();
+    let ret = match handler {
+        Some(handler) => handler(&args),
+        None => Err(ENOSYS),
+    };
+
+    frame.a0 = match ret {
+        Ok(value) => value,
+        Err(errno) => (-errno) as usize,
+    };
+    frame.sepc += 4;
+}
+
+impl IpcError {
+    fn errno(&self) -> isize {
+        match self {
+            IpcError::SelfSend => EINVAL,
+            IpcError::DeadlockDetected => EDEADLK,
+            IpcError::SendQueueFull => EAGAIN,
+            IpcError::NoSuchProcess => ESRCH,
+            IpcError::UnexpectedState => EIO,
+            IpcError::Busy => EBUSY,
+        }
+    }
+}
+
+impl ElfError {
+    fn errno(&self) -> isize {
+        match self {
+            ElfError::ArgumentsTooLarge => E2BIG,
+            ElfError::PageLimit | ElfError::OutOfMemory => ENOMEM,
+            ElfError::TableFull => EAGAIN,
+            _ => ENOEXEC,
+        }
+    }
+}
+
+fn user_range(ptr: usize, len: usize) -> Result<VirtAddr, isize> {
+    ptr.checked_add(len).ok_or(EFAULT)?;
+    Ok(VirtAddr::from_usize(ptr))
+}
+
+fn user_string(ptr: usize, len: usize) -> Result<String, isize> {
+    if len > PAGE_SIZE {
+        return Err(E2BIG);
+    }
+    let src = user_range(ptr, len)?;
+
+    let mut buf = vec![0u8; len];
+    if !copy_from_user(PM.current_page_table(), src, &mut buf) {
+        return Err(EFAULT);
+    }
+    String::from_utf8(buf).map_err(|_| EINVAL)
+}
+
+// Reads a program name and an argv of `argc` (pointer, length) pairs
+fn user_command(args: &[usize; 6]) -> Result<(&'static Program, Vec<String>), isize> {
+    let [name_ptr, name_len, argv_ptr, argc, ..] = *args;
+    if argc > ARGV_MAX {
+        return Err(E2BIG);
+    }
+
+    let name = user_string(name_ptr, name_len)?;
+    let program = programs::find(&name).ok_or(ENOENT)?;
+
+    let pair_size = 2 * size_of::<usize>();
+    let src = user_range(argv_ptr, argc * pair_size)?;
+    let mut pairs = vec![0u8; argc * pair_size];
+    if !copy_from_user(PM.current_page_table(), src, &mut pairs) {
+        return Err(EFAULT);
+    }
+
+    let argv = pairs
+        .chunks(pair_size)
+        .map(|pair| {
+            let (ptr, len) = pair.split_at(size_of::<usize>());
+            let ptr = usize::from_le_bytes(ptr.try_into().unwrap());
+            let len = usize::from_le_bytes(len.try_into().unwrap());
+            user_string(ptr, len)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((program, argv))
+}
+
+// Processes may only manage themselves and their children
+fn own_or_child(pid: usize) -> Result<Pid, isize> {
+    let pid = Pid::from_raw(pid);
+    let me = PM.current_pid();
+    if pid != me && PM.parent(pid).ok_or(ESRCH)? != me {
+        return Err(EPERM);
+    }
+    Ok(pid)
+}
+
+fn check_children_limit() -> Result<(), isize> {
+    let me = PM.current_pid();
+    if PM.children(me) >= PM.limits(me).ok_or(ESRCH)?.children {
+        return Err(EAGAIN);
+    }
+    Ok(())
+}
+
+fn sys_exit(_: &[usize; 6]) -> SysResult {
+    PM.exit();
+}
+
+fn sys_yield(_: &[usize; 6]) -> SysResult {
+    PM.switch();
+    Ok(0)
+}
+
+fn sys_getpid(_: &[usize; 6]) -> SysResult {
+    Ok(PM.current_pid().as_raw())
+}
+
+// a0: microseconds
+fn sys_sleep(args: &[usize; 6]) -> SysResult {
+    sleep(Duration::from_micros(args[0] as u64));
+    Ok(0)
+}
+
+// a0: buffer, a1: length. The text goes out in chunks through a buffer on the stack, so any
+// length works without allocating; output stops at the first byte that is not UTF-8.
+fn sys_write(args: &[usize; 6]) -> SysResult {
+    let [ptr, len, ..] = *args;
+    user_range(ptr, len)?;
+
+    let page_table = PM.current_page_table();
+    let mut buf = [0u8; WRITE_CHUNK];
+    // the start of a character the previous chunk cut off
+    let mut carry = 0;
+    let mut copied = 0;
+    while copied < len {
+        let count = (WRITE_CHUNK - carry).min(len - copied);
+        let src = VirtAddr::from_usize(ptr + copied);
+        if !copy_from_user(page_table, src, &mut buf[carry..carry + count]) {
+            return Err(EFAULT);
+        }
+        copied += count;
+
+        let filled = carry + count;
+        let valid = match str::from_utf8(&buf[..filled]) {
+            Ok(text) => text.len(),
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            Err(_) => return Err(EINVAL),
+        };
+        if let Ok(text) = str::from_utf8(&buf[..valid]) {
+            print!("{text}");
+        }
+        buf.copy_within(valid..filled, 0);
+        carry = filled - valid;
+    }
+
+    if carry != 0 {
+        return Err(EINVAL);
+    }
+    Ok(len)
+}
+
+// a0: destination pid, a1: message of MESSAGE_WORDS words
+fn sys_send(args: &[usize; 6]) -> SysResult {
+    let [dst, ptr, ..] = *args;
+    let src = user_range(ptr, MESSAGE_WORDS * size_of::<usize>())?;
+
+    let mut bytes = [0u8; MESSAGE_WORDS * size_of::<usize>()];
+    if !copy_from_user(PM.current_page_table(), src, &mut bytes) {
+        return Err(EFAULT);
+    }
+
+    let mut words = [0usize; MESSAGE_WORDS];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(size_of::<usize>())) {
+        *word = usize::from_le_bytes(chunk.try_into().unwrap());
+    }
+    let msg = Message::decode(&words).ok_or(EINVAL)?;
+
+    Ipc::send(Pid::from_raw(dst), msg).map_err(|err| err.errno())?;
+    Ok(0)
+}
+
+// a0: source pid or SRC_ANY, a1: buffer for MESSAGE_WORDS words
+fn sys_recv(args: &[usize; 6]) -> SysResult {
+    let [src, ptr, ..] = *args;
+    let dst = user_range(ptr, MESSAGE_WORDS * size_of::<usize>())?;
+
+    let src = if src == SRC_ANY {
+        Src::Any
+    } else {
+        Src::Specific(Pid::from_raw(src))
+    };
+    let msg = Ipc::recv(src).map_err(|err| err.errno())?;
+
+    let mut bytes = [0u8; MESSAGE_WORDS * size_of::<usize>()];
+    for (chunk, word) in bytes.chunks_mut(size_of::<usize>()).zip(msg.encode()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    if !copy_to_user(PM.current_page_table(), dst, &bytes) {
+        return Err(EFAULT);
+    }
+    Ok(0)
+}
+
+// a0: new program break, or 0 to query the current one
+fn sys_brk(args: &[usize; 6]) -> SysResult {
+    PM.set_brk(args[0]).ok_or(ENOMEM)
+}
+
+// a0: increment; returns the old program break
+fn sys_sbrk(args: &[usize; 6]) -> SysResult {
+    PM.sbrk(args[0]).ok_or(ENOMEM)
+}
+
+// a0, a1: program name, a2: argv as (pointer, length) pairs, a3: argc; the process is named
+// after argv[0]
+fn sys_spawn(args: &[usize; 6]) -> SysResult {
+    check_children_limit()?;
+
+    let (program, argv) = user_command(args)?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let name = argv.first().copied().unwrap_or(program.name);
+
+    PM.create_user_process(name, program.image, &argv, PM.current_priority())
+        .map(|pid| pid.as_raw())
+        .map_err(|err| err.errno())
+}
+
+// Same arguments as SYS_SPAWN; does not return on success
+fn sys_exec(args: &[usize; 6]) -> SysResult {
+    let (program, argv) = user_command(args)?;
+
+    // exec takes the arguments along, as nothing left on this stack is ever dropped
+    match PM.exec(program.name, program.image, argv) {
+        Err(err) => Err(err.errno()),
+    }
+}
+
+impl SchedError {
+    fn errno(&self) -> isize {
+        match self {
+            SchedError::NoSuchProcess => ESRCH,
+            SchedError::InvalidParameters => EINVAL,
+            SchedError::AdmissionDenied => EBUSY,
+            SchedError::TableFull => EAGAIN,
+            SchedError::OutOfMemory => ENOMEM,
+        }
+    }
+}
+
+// a0: pid of the caller or one of its children, a1: SCHED_MLFQ, SCHED_FAIR or SCHED_EDF,
+// a2-a4: priority level, weight, or period, budget and deadline in microseconds
+fn sys_sched_set(args: &[usize; 6]) -> SysResult {
+    let [pid, class, param, ..] = *args;
+    let pid = own_or_child(pid)?;
+
+    match class {
+        SCHED_MLFQ => {
+            // nobody gets a higher priority than its own
+            let priority = Priority::new(param);
+            if priority < PM.current_priority() {
+                return Err(EPERM);
+            }
+            PM.set_class(pid, SchedClass::Mlfq)
+                .map_err(|err| err.errno())?;
+            PM.set_priority(pid, priority);
+        }
+        SCHED_FAIR => {
+            let weight = param as u64;
+            PM.set_class(pid, SchedClass::Fair { weight })
+                .map_err(|err| err.errno())?;
+        }
+        SCHED_EDF => {
+            let [period, budget, deadline] =
+                [args[2], args[3], args[4]].map(|us| us_to_ticks(us as u64));
+            PM.set_class(
+                pid,
+                SchedClass::RealTime {
+                    period,
+                    budget,
+                    deadline,
+                },
+            )
+            .map_err(|err| err.errno())?;
+        }
+        _ => return Err(EINVAL),
+    }
+    Ok(0)
+}
+
+impl ThreadError {
+    fn errno(&self) -> isize {
+        match self {
+            ThreadError::NoSuchThread => ESRCH,
+            ThreadError::Deadlock => EDEADLK,
+            ThreadError::AlreadyJoined => EINVAL,
+        }
+    }
+}
+
+// a0: entry point, a1: stack pointer, a2: argument handed to the entry point in a0
+fn sys_thread_create(args: &[usize; 6]) -> SysResult {
+    let [entry, sp, arg, ..] = *args;
+    if PM.thread_count(PM.current_pid()) >= PROC_THREADS_MAX {
+        return Err(EAGAIN);
+    }
+    PM.create_user_thread(entry, sp, arg)
+        .map(|tid| tid.as_raw())
+        .map_err(|err| err.errno())
+}
+
+// a0: exit code for whoever joins the thread
+fn sys_thread_exit(args: &[usize; 6]) -> SysResult {
+    PM.exit_thread(args[0]);
+}
+
+// a0: thread of the calling process; returns its exit code
+fn sys_thread_join(args: &[usize; 6]) -> SysResult {
+    PM.join(Tid::from_raw(args[0])).map_err(|err| err.errno())
+}
+
+fn resource(number: usize) -> Result<Resource, isize> {
+    match number {
+        RLIMIT_PAGES => Ok(Resource::Pages),
+        RLIMIT_CPU => Ok(Resource::CpuTime),
+        RLIMIT_CHILDREN => Ok(Resource::Children),
+        RLIMIT_IPC_QUEUE => Ok(Resource::IpcQueue),
+        RLIMIT_SHM => Ok(Resource::SharedMemory),
+        _ => Err(EINVAL),
+    }
+}
+
+// CPU time is kept in ticks but handed over in microseconds
+fn limit_from_user(resource: Resource, value: usize) -> u64 {
+    match (resource, value) {
+        (_, RLIM_INFINITY) => u64::MAX,
+        (Resource::CpuTime, us) => us_to_ticks(us as u64),
+        (_, count) => count as u64,
+    }
+}
+
+fn limit_to_user(resource: Resource, value: u64) -> usize {
+    let value = match resource {
+        Resource::CpuTime if value != u64::MAX => value.saturating_mul(1_000_000) / TIMEBASE_FREQ,
+        _ => value,
+    };
+    value.min(RLIM_INFINITY as u64) as usize
+}
+
+// a0: pid, a1: RLIMIT_*; returns the limit or RLIM_INFINITY
+fn sys_getrlimit(args: &[usize; 6]) -> SysResult {
+    let [pid, number, ..] = *args;
+    let resource = resource(number)?;
+    let limits = PM.limits(Pid::from_raw(pid)).ok_or(ESRCH)?;
+    Ok(limit_to_user(resource, limits.get(resource)))
+}
+
+// a0: pid of the caller or one of its children, a1: RLIMIT_*, a2: new limit or RLIM_INFINITY.
+// Nobody gets more than the caller has, so limits only ever go down the process tree.
+fn sys_setrlimit(args: &[usize; 6]) -> SysResult {
+    let [pid, number, value, ..] = *args;
+    let pid = own_or_child(pid)?;
+    let resource = resource(number)?;
+
+    let value = limit_from_user(resource, value);
+    let mine = PM.limits(PM.current_pid()).ok_or(ESRCH)?;
+    if value > mine.get(resource) {
+        return Err(EPERM);
+    }
+
+    if !PM.set_limit(pid, resource, value) {
+        return Err(ESRCH);
+    }
+    Ok(0)
+}
+
+// a0: pid of the caller or one of its children, a1: WATCHDOG_REPORT, WATCHDOG_KILL or
+// WATCHDOG_RESTART
+fn sys_watchdog(args: &[usize; 6]) -> SysResult {
+    let [pid, action, ..] = *args;
+    let pid = own_or_child(pid)?;
+    let action = match action {
+        WATCHDOG_REPORT => Action::Report,
+        WATCHDOG_KILL => Action::Kill,
+        WATCHDOG_RESTART => Action::Restart,
+        _ => return Err(EINVAL),
+    };
+
+    if !PM.set_watchdog(pid, action) {
+        return Err(ESRCH);
+    }
+    Ok(0)
+}
+
+fn sys_set_affinity(args: &[usize; 6]) -> SysResult {
+    let [pid, mask, ..] = *args;
+    let pid = own_or_child(pid)?;
+    let affinity = Affinity::from_mask(mask).ok_or(EINVAL)?;
+
+    if !PM.set_affinity(pid, affinity) {
+        return Err(ESRCH);
+    }
+    Ok(0)
+}
+
+impl CheckpointError {
+    fn errno(&self) -> isize {
+        match self {
+            CheckpointError::NoSuchProcess => ESRCH,
+            CheckpointError::BufferTooSmall => ERANGE,
+            CheckpointError::BadAddress => EFAULT,
+            CheckpointError::Corrupt => EINVAL,
+            CheckpointError::PageLimit | CheckpointError::OutOfMemory => ENOMEM,
+            CheckpointError::QueueLimit => EAGAIN,
+            CheckpointError::Running => EAGAIN,
+            CheckpointError::TableFull => EAGAIN,
+        }
+    }
+}
+
+// a0: pid of a child, a1: buffer, a2: its length, or 0 to query the size of the blob; returns
+// the size. The child is written out as it was when it last left the CPU.
+fn sys_checkpoint(args: &[usize; 6]) -> SysResult {
+    let [pid, ptr, len, ..] = *args;
+    let pid = own_or_child(pid)?;
+    if pid == PM.current_pid() {
+        return Err(EINVAL);
+    }
+    user_range(ptr, len)?;
+
+    checkpoint::save(pid, ptr, len).map_err(|err| err.errno())
+}
+
+// a0: blob written by SYS_CHECKPOINT, a1: its length; returns the pid of the new child
+fn sys_restore(args: &[usize; 6]) -> SysResult {
+    let [ptr, len, ..] = *args;
+    check_children_limit()?;
+    user_range(ptr, len)?;
+
+    checkpoint::restore(ptr, len)
+        .map(|pid| pid.as_raw())
+        .map_err(|err| err.errno())
+}
+
+impl FutexError {
+    fn errno(&self) -> isize {
+        match self {
+            FutexError::WouldBlock => EAGAIN,
+            FutexError::TimedOut => ETIMEDOUT,
+            FutexError::BadAddress => EFAULT,
+        }
+    }
+}
+
+// a0: address of a word, a1: FUTEX_WAIT or FUTEX_WAKE, a2: the value the word has to hold for
+// FUTEX_WAIT, or how many waiters FUTEX_WAKE wakes, a3: timeout of FUTEX_WAIT in microseconds,
+// or 0 to wait for good. FUTEX_WAKE returns the number of threads woken.
+fn sys_futex(args: &[usize; 6]) -> SysResult {
+    let [addr, op, val, timeout, ..] = *args;
+    if addr % size_of::<usize>() != 0 {
+        return Err(EINVAL);
+    }
+
+    match op {
+        FUTEX_WAIT => {
+            let timeout = (timeout != 0).then(|| Duration::from_micros(timeout as u64));
+            futex::wait(addr, val, timeout).map_err(|err| err.errno())?;
+            Ok(0)
+        }
+        FUTEX_WAKE => Ok(futex::wake(addr, val)),
+        _ => Err(EINVAL),
+    }
+}
+
+impl ShmError {
+    fn errno(&self) -> isize {
+        match self {
+            ShmError::InvalidSize | ShmError::NotAttached => EINVAL,
+            ShmError::Limit | ShmError::OutOfMemory => ENOMEM,
+        }
+    }
+}
+
+// a0: key shared by the processes, a1: size in bytes, which has to match if the segment exists
+// already; returns the address the segment is mapped at
+fn sys_shm_attach(args: &[usize; 6]) -> SysResult {
+    let [key, size, ..] = *args;
+    shm::attach(key, size.div_ceil(PAGE_SIZE)).map_err(|err| err.errno())
+}
+
+// a0: address SYS_SHM_ATTACH returned
+fn sys_shm_detach(args: &[usize; 6]) -> SysResult {
+    shm::detach(args[0]).map_err(|err| err.errno())?;
+    Ok(0)
+}
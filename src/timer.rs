@@ -1,13 +1,26 @@
-use core::arch::asm;
+use abi::TIMEBASE_FREQ;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{
+    arch::asm,
+    ops::{Add, AddAssign},
+    time::Duration,
+};
 
-use crate::{constants::TIMER_QUANTUM_US, sbi::sbi_call, utils::irq_enable, write_csr_set};
+use crate::{
+    constants::{MAX_HARTS, SCHED_TICK_US, WATCHDOG_PERIOD_US},
+    hart,
+    process::{PM, Tid},
+    sbi::sbi_call,
+    sync::SpinLock,
+    utils::irq_enable,
+    wait_queue::WaitQueue,
+    write_csr_set,
+};
 
 const SBI_EID_TIME: usize = 0x54494d45;
 const SBI_FID_SET_TIMER: usize = 0;
 
-const TIMEBASE_FREQ: u64 = 10_000_000; // 10 MHz
-
-fn get_time() -> u64 {
+pub fn get_time() -> u64 {
     let (mut hi, mut lo, mut tmp): (u32, u32, u32);
     loop {
         unsafe {
@@ -22,20 +35,103 @@ fn get_time() -> u64 {
     ((hi as u64) << 32) | lo as u64
 }
 
-fn set_next_timer() {
-    let now = get_time();
-    let delta = TIMER_QUANTUM_US * TIMEBASE_FREQ / 1_000_000;
-    let next = now + delta;
+pub const fn us_to_ticks(us: u64) -> u64 {
+    us * TIMEBASE_FREQ / 1_000_000
+}
+
+const fn duration_to_ticks(duration: Duration) -> u64 {
+    duration.as_secs() * TIMEBASE_FREQ
+        + duration.subsec_nanos() as u64 * TIMEBASE_FREQ / 1_000_000_000
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(get_time())
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Instant(ticks)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
 
-    let lo = next as u32 as usize;
-    let hi = (next >> 32) as u32 as usize;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + duration_to_ticks(rhs))
+    }
+}
 
-    let sbi_ret = sbi_call(lo, hi, 0, 0, 0, 0, SBI_EID_TIME, SBI_FID_SET_TIMER);
-    if let Err(err) = sbi_ret {
-        panic!("Failed to set timer: {err}");
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
     }
 }
 
+// Sleeping threads ordered by deadline. The timer IRQ moves the expired ones to `expired`, and
+// the scheduler makes them runnable on its next pass, so the IRQ never touches the thread table.
+struct TimerQueue {
+    sleepers: Vec<(Instant, Tid)>,
+    expired: VecDeque<Tid>,
+    // per hart, the end of the budget of the real-time thread running there
+    preempt_at: [Option<Instant>; MAX_HARTS],
+    // the watchdog needs the thread table, so the IRQ only flags a scan as due
+    watchdog_due: bool,
+    next_watchdog: u64,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        TimerQueue {
+            sleepers: Vec::new(),
+            expired: VecDeque::new(),
+            preempt_at: [None; MAX_HARTS],
+            watchdog_due: false,
+            next_watchdog: 0,
+        }
+    }
+
+    fn insert(&mut self, deadline: Instant, tid: Tid) {
+        let pos = self.sleepers.partition_point(|(d, _)| *d <= deadline);
+        self.sleepers.insert(pos, (deadline, tid));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let count = self.sleepers.partition_point(|(d, _)| *d <= now);
+        self.expired
+            .extend(self.sleepers.drain(..count).map(|(_, tid)| tid));
+    }
+
+    fn earliest(&self) -> Option<Instant> {
+        self.sleepers.first().map(|(d, _)| *d)
+    }
+
+    // Programs the timer of the calling hart
+    fn set_next_timer(&self) {
+        let now = get_time();
+        let mut next = now + us_to_ticks(SCHED_TICK_US);
+        if let Some(deadline) = self.earliest() {
+            next = next.min(deadline.0);
+        }
+        if let Some(preempt_at) = self.preempt_at[hart::id()] {
+            next = next.min(preempt_at.0.max(now));
+        }
+
+        let lo = next as u32 as usize;
+        let hi = (next >> 32) as u32 as usize;
+
+        let sbi_ret = sbi_call(lo, hi, 0, 0, 0, 0, SBI_EID_TIME, SBI_FID_SET_TIMER);
+        if let Err(err) = sbi_ret {
+            panic!("Failed to set timer: {err}");
+        }
+    }
+}
+
+static TIMERS: SpinLock<TimerQueue> = SpinLock::new(TimerQueue::new());
+
 pub fn enable_timer_irq() {
     unsafe {
         write_csr_set!("sie", 1 << 5); // STIE
@@ -45,11 +141,80 @@ pub fn enable_timer_irq() {
 
 pub fn init_timer() {
     enable_timer_irq();
-    set_next_timer();
+    TIMERS.lock().set_next_timer();
 }
 
 pub fn handle_timer_irq() {
-    set_next_timer();
+    let now = Instant::now();
+    let mut timers = TIMERS.lock();
+    timers.expire(now);
+
+    if now.0 >= timers.next_watchdog {
+        timers.watchdog_due = true;
+        timers.next_watchdog = now.0 + us_to_ticks(WATCHDOG_PERIOD_US);
+    }
+
+    timers.set_next_timer();
+}
+
+// Whether the watchdog should scan now; called where no thread table locks are held
+pub fn take_watchdog_due() -> bool {
+    let mut timers = TIMERS.lock();
+    let due = timers.watchdog_due;
+    timers.watchdog_due = false;
+    due
+}
+
+// Called by the scheduler for each thread whose deadline has passed, one at a time so that the
+// queue keeps its buffer and is not locked while the thread is woken
+pub fn pop_expired() -> Option<Tid> {
+    let mut timers = TIMERS.lock();
+    if timers.expired.is_empty() {
+        timers.expire(Instant::now());
+    }
+    timers.expired.pop_front()
+}
+
+// Only meaningful with interrupts disabled
+pub fn has_expired() -> bool {
+    !TIMERS.lock().expired.is_empty()
+}
+
+// Hands `tid` to the scheduler once `deadline` has passed
+pub fn wake_at(deadline: Instant, tid: Tid) {
+    let mut timers = TIMERS.lock();
+    timers.insert(deadline, tid);
+    timers.set_next_timer();
+}
+
+// Also takes back a deadline that passed but has not reached the scheduler yet
+pub fn cancel_timers(tid: Tid) {
+    let mut timers = TIMERS.lock();
+    timers.sleepers.retain(|(_, t)| *t != tid);
+    timers.expired.retain(|t| *t != tid);
+}
+
+// Makes sure the timer fires by `at` to preempt the thread being dispatched
+pub fn set_preemption(at: Option<Instant>) {
+    let mut timers = TIMERS.lock();
+    let preempt_at = &mut timers.preempt_at[hart::id()];
+    if at.is_none() && preempt_at.is_none() {
+        return;
+    }
+    *preempt_at = at;
+    timers.set_next_timer();
+}
+
+pub fn sleep_until(deadline: Instant) {
+    if deadline <= Instant::now() {
+        PM.switch();
+        return;
+    }
+
+    // nobody else knows the queue, so only the deadline ends the wait
+    WaitQueue::new().wait_until(deadline);
+}
 
-    let now = get_time();
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
 }
@@ -1,6 +1,18 @@
-use core::{arch::naked_asm, panic};
+use core::{arch::naked_asm, fmt::Write, panic};
 
-use crate::{read_csr, timer::handle_timer_irq};
+use crate::{
+    constants::SSTATUS_SPP,
+    hart, print, println,
+    process::PM,
+    read_csr,
+    sync::{holds_kernel_lock, lock_kernel, unlock_kernel},
+    syscall::handle_syscall,
+    timer::{handle_timer_irq, take_watchdog_due},
+    watchdog,
+};
+
+// A trap from U-mode leaves its frame this far below the top of the kernel stack
+pub const TRAP_FRAME_SPACE: usize = 4 * 48;
 
 #[unsafe(naked)]
 #[repr(align(16))]
@@ -26,7 +38,7 @@ pub unsafe extern "C" fn kernel_entry() {
         addi a0, sp, 0
 
         2:
-        addi a0, a0, -4 * 48
+        addi a0, a0, -{frame_space}
 
         sw sp, 4 * 32(a0)
         csrrw sp, sscratch, a0
@@ -70,14 +82,40 @@ pub unsafe extern "C" fn kernel_entry() {
         csrr a0, sepc
         sw a0, 4 * 31(sp)
 
+        // the hart id kept in the sscratch block, in case U-mode changed tp
+        csrr a0, sscratch
+        lw tp, 4 * 2(a0)
+
         mv a0, sp
         call {handle_trap}
+        j {trap_return}
+        ",
+        frame_space = const TRAP_FRAME_SPACE,
+        handle_trap = sym handle_trap,
+        trap_return = sym trap_return,
+    );
+}
 
+// Returns to where the trap frame at sp was taken
+#[unsafe(naked)]
+#[repr(align(4))]
+pub unsafe extern "C" fn trap_return() -> ! {
+    naked_asm!(
+        "
         lw a0, 4 * 31(sp)
         csrw sepc, a0
         lw a0, 4 * 30(sp)
         csrw sstatus, a0
 
+        // a kernel thread may be resumed on another hart than the one it trapped on, so back to
+        // S-mode tp is the hart id kept in the sscratch block, not the one in the frame
+        andi a0, a0, (1 << 8)
+        beqz a0, 1f
+        csrr a0, sscratch
+        lw a0, 4 * 2(a0)
+        sw a0, 4 * 2(sp)
+        1:
+
         lw ra,  4 * 0(sp)
         lw gp,  4 * 1(sp)
         lw tp,  4 * 2(sp)
@@ -113,53 +151,64 @@ pub unsafe extern "C" fn kernel_entry() {
 
         sret
         ",
-        handle_trap = sym handle_trap,
     );
 }
 
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub struct TrapFrame {
-    ra: usize,
-    gp: usize,
-    tp: usize,
-    t0: usize,
-    t1: usize,
-    t2: usize,
-    t3: usize,
-    t4: usize,
-    t5: usize,
-    t6: usize,
-    a0: usize,
-    a1: usize,
-    a2: usize,
-    a3: usize,
-    a4: usize,
-    a5: usize,
-    a6: usize,
-    a7: usize,
-    s0: usize,
-    s1: usize,
-    s2: usize,
-    s3: usize,
-    s4: usize,
-    s5: usize,
-    s6: usize,
-    s7: usize,
-    s8: usize,
-    s9: usize,
-    s10: usize,
-    s11: usize,
-    sstatus: usize,
-    sepc: usize,
-    sp: usize,
+    pub ra: usize,
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub sp: usize,
 }
 
 enum TrapCause {
+    Software = 1,
     Timer = 5,
 }
 
-fn handle_trap(_: &TrapFrame) {
+const SCAUSE_USER_ECALL: usize = 8;
+
+fn handle_trap(frame: &mut TrapFrame) {
+    // kernel threads run outside the kernel lock between calls into the kernel, and are treated
+    // like user processes there
+    let from_user = frame.sstatus & SSTATUS_SPP == 0 || !holds_kernel_lock();
+    if from_user {
+        lock_kernel();
+        PM.account_user_time();
+        PM.exit_if_killed();
+    }
+
     let scause = read_csr!("scause");
     let stval = read_csr!("stval");
     let sepc = read_csr!("sepc");
@@ -170,12 +219,44 @@ fn handle_trap(_: &TrapFrame) {
         match irq {
             val if val == TrapCause::Timer as usize => {
                 handle_timer_irq();
+                // kernel code is not written to be preempted, so only code outside it is
+                if from_user {
+                    if take_watchdog_due() {
+                        watchdog::check();
+                    }
+                    PM.switch();
+                }
+            }
+            val if val == TrapCause::Software as usize => {
+                hart::ack_reschedule();
+                // the idle loop looks at the run queue once it wakes up anyway
+                if from_user {
+                    PM.switch();
+                }
             }
             _ => {
                 panic!("unexpected IRQ scause: {scause:x}, stval: {stval:x}, sepc: {sepc:x}");
             }
         }
+    } else if from_user && scause == SCAUSE_USER_ECALL {
+        handle_syscall(frame);
+    } else if from_user {
+        println!(
+            "killing {}: unexpected trap scause: {scause:x}, stval: {stval:x}, sepc: {sepc:x}",
+            PM.current_pid()
+        );
+        PM.exit();
     } else {
         panic!("unexpected trap scause: {scause:x}, stval: {stval:x}, sepc: {sepc:x}");
     }
+
+    if from_user && PM.cpu_limit_exceeded() {
+        println!("killing {}: CPU time limit exceeded", PM.current_pid());
+        PM.exit();
+    }
+
+    if from_user {
+        PM.account_system_time();
+        unlock_kernel();
+    }
 }
@@ -0,0 +1,96 @@
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+use crate::{
+    process::{PM, Tid},
+    sync::SpinLock,
+    timer::{Instant, wake_at},
+};
+
+// Threads blocked until someone wakes them, in the order they started waiting. A thread that
+// is torn down while waiting stays queued, and waking it is a no-op that passes the wake-up on.
+pub struct WaitQueue {
+    waiters: SpinLock<VecDeque<Tid>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+
+    pub fn contains(&self, tid: Tid) -> bool {
+        self.waiters.lock().contains(&tid)
+    }
+
+    // Queues and blocks the current thread, which goes to sleep at the next `PM.switch()`. For
+    // callers that have locks to drop before switching; everyone else calls `wait`.
+    pub fn prepare_wait(&self) {
+        self.waiters.lock().push_back(PM.current_tid());
+        PM.block_current();
+    }
+
+    pub fn wait(&self) {
+        self.prepare_wait();
+        PM.switch();
+    }
+
+    // Like `wait`, but gives up at `deadline`. Returns whether the thread was woken; a wake-up
+    // racing with the deadline may count as either.
+    pub fn wait_until(&self, deadline: Instant) -> bool {
+        if deadline <= Instant::now() {
+            return false;
+        }
+
+        let tid = PM.current_tid();
+        self.waiters.lock().push_back(tid);
+        PM.sleep_current();
+        wake_at(deadline, tid);
+        PM.switch();
+
+        // still queued means the timer got there first
+        !self.remove(tid)
+    }
+
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.wait_until(Instant::now() + timeout)
+    }
+
+    // Returns whether there was a thread to wake
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(tid) = self.waiters.lock().pop_front() else {
+                return false;
+            };
+            if PM.wake(tid) {
+                return true;
+            }
+        }
+    }
+
+    // Returns the number of threads woken
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+
+    // Takes `tid` off the queue without waking it; returns whether it was queued
+    pub fn remove(&self, tid: Tid) -> bool {
+        let mut waiters = self.waiters.lock();
+        let queued = waiters.len();
+        waiters.retain(|t| *t != tid);
+        waiters.len() != queued
+    }
+
+    pub fn clear(&self) {
+        self.waiters.lock().clear();
+    }
+}
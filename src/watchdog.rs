@@ -0,0 +1,193 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use crate::{
+    constants::{MAX_HARTS, WATCHDOG_TIMEOUT_US},
+    hart,
+    ipc::Src,
+    memory::free_pages,
+    print, println,
+    process::{PM, Pid, ProcInfo, State, WaitReason},
+    sync::{SpinLock, kernel_lock_wait},
+    timer::{get_time, us_to_ticks},
+};
+
+// What the watchdog does to a process it finds stuck, besides reporting it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Report,
+    Kill,
+    Restart,
+}
+
+#[derive(Clone, Copy)]
+enum Stall {
+    Send,
+    Starved,
+    QueueFull,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Stall::Send => "blocked in send",
+            Stall::Starved => "runnable but not scheduled",
+            Stall::QueueFull => "retrying sends to a full queue",
+        })
+    }
+}
+
+// Stalls found by the last scan, by process and the time the stall began, so that each one is
+// reported only once
+static REPORTED: SpinLock<Vec<(Pid, u64)>> = SpinLock::new(Vec::new());
+
+// Looks for processes that have made no progress for WATCHDOG_TIMEOUT_US: a thread blocked in
+// send or runnable without getting the CPU, or sends that keep failing on a full queue. The
+// timer IRQ flags a scan as due; this runs from the idle loop and from traps out of U-mode, where
+// no locks on the process table are held. The running process is left for a later scan. Scans
+// that find something new also list the whole process table.
+pub fn check() {
+    let now = get_time();
+    let timeout = us_to_ticks(WATCHDOG_TIMEOUT_US);
+    let current = PM.current_pid();
+
+    let mut stalls: Vec<(Pid, u64, Stall)> = Vec::new();
+    for slot in PM.threads() {
+        let thread = slot.lock();
+        if thread.pid == current || thread.pid.is_idle() {
+            continue;
+        }
+
+        let stall = match thread.state {
+            State::Blocked => {
+                let proc = PM.slot(thread.pid).lock();
+                if proc.ipc.pending_send.is_none() || !proc.ipc.waiters.contains(thread.tid) {
+                    continue;
+                }
+                Stall::Send
+            }
+            State::Runnable => Stall::Starved,
+            _ => continue,
+        };
+        if now - thread.since >= timeout && !stalls.iter().any(|(pid, ..)| *pid == thread.pid) {
+            stalls.push((thread.pid, thread.since, stall));
+        }
+    }
+
+    for slot in PM.slots() {
+        let proc = slot.lock();
+        if !proc.alive || proc.pid == current || stalls.iter().any(|(pid, ..)| *pid == proc.pid) {
+            continue;
+        }
+        if let Some(since) = proc.ipc.full_since
+            && now - since >= timeout
+        {
+            stalls.push((proc.pid, since, Stall::QueueFull));
+        }
+    }
+
+    let fresh: Vec<_> = {
+        let mut reported = REPORTED.lock();
+        let fresh = stalls
+            .iter()
+            .filter(|(pid, since, _)| !reported.contains(&(*pid, *since)))
+            .copied()
+            .collect();
+        *reported = stalls
+            .iter()
+            .map(|(pid, since, _)| (*pid, *since))
+            .collect();
+        fresh
+    };
+
+    if !fresh.is_empty() {
+        print_table();
+    }
+
+    for (pid, since, stall) in fresh {
+        // an earlier kill may have taken this one along
+        let Some(slot) = PM.get(pid) else {
+            continue;
+        };
+        let (name, action) = {
+            let proc = slot.lock();
+            (proc.name, proc.watchdog)
+        };
+
+        println!(
+            "watchdog: {name} ({pid}) {stall} for {} ms",
+            (now - since) / us_to_ticks(1_000)
+        );
+        println!("watchdog:   {}", wait_chain(pid));
+
+        match action {
+            Action::Report => {}
+            Action::Kill => {
+                println!("watchdog: killing {pid}");
+                PM.kill(pid);
+            }
+            Action::Restart => match PM.restart(pid) {
+                Ok(()) => println!("watchdog: restarted {pid}"),
+                Err(err) => println!("watchdog: failed to restart {pid}: {err:?}"),
+            },
+        }
+    }
+}
+
+fn print_table() {
+    println!(
+        "watchdog: harts {}% busy since boot, {} ms idle, load average {}, {} pages free",
+        PM.utilization(),
+        PM.idle_time() / us_to_ticks(1_000),
+        PM.load_average(),
+        free_pages()
+    );
+    let mut waits = String::new();
+    for hart in (0..MAX_HARTS).filter(|&hart| hart::is_online(hart)) {
+        let _ = write!(
+            waits,
+            ", hart {hart} {} ms",
+            kernel_lock_wait(hart) / us_to_ticks(1_000)
+        );
+    }
+    println!("watchdog: waited for the kernel lock{waits}");
+    println!("watchdog: {}", ProcInfo::header());
+    for info in PM.snapshot() {
+        println!("watchdog: {info}");
+    }
+}
+
+// Follows whom each process waits for, the destination of its send or the source it receives
+// from, until someone who waits for nobody or a cycle
+fn wait_chain(start: Pid) -> String {
+    let mut chain = String::new();
+    let mut seen = Vec::new();
+    let mut pid = start;
+    loop {
+        let Some(slot) = PM.get(pid) else {
+            let _ = write!(chain, "{pid} (gone)");
+            break;
+        };
+        let proc = slot.lock();
+        let _ = write!(chain, "{} ({pid})", proc.name);
+        seen.push(pid);
+
+        let (arrow, next) = match WaitReason::of(&proc) {
+            Some(WaitReason::Send(dst)) => (" -> ", dst),
+            Some(WaitReason::Recv(Src::Specific(src))) => (" <- ", src),
+            Some(wait @ WaitReason::Recv(Src::Any)) => {
+                let _ = write!(chain, " {wait}");
+                break;
+            }
+            None => break,
+        };
+        chain.push_str(arrow);
+
+        if seen.contains(&next) {
+            let _ = write!(chain, "{next} (cycle)");
+            break;
+        }
+        pid = next;
+    }
+    chain
+}
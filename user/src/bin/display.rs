@@ -1,10 +1,10 @@
-use core::fmt::Write;
+#![no_std]
+#![no_main]
 
-use crate::ipc::{Ipc, Message, Src};
-use crate::process::Pid;
-use crate::{print, println};
+use core::fmt::Write;
 
-pub const DISPLAY_SERVER_PID: Pid = Pid::new(1);
+use user::ipc::{Ipc, Message, Src};
+use user::{print, println};
 
 const QUAD_WIDTH: u8 = 105;
 const QUAD_HEIGHT: u8 = 25;
@@ -110,7 +110,7 @@ fn draw_cell(display: u8, x: u8, y: u8, fg: u8, bg: u8, ch: char) {
     ansi_reset();
 }
 
-pub fn display_server() -> ! {
+fn display_server() -> ! {
     ansi_hide_cursor();
     ansi_clear_screen();
     draw_separators();
@@ -154,3 +154,8 @@ pub fn display_server() -> ! {
         }
     }
 }
+
+#[unsafe(no_mangle)]
+fn main() {
+    display_server();
+}
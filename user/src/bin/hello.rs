@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate alloc;
+
+use core::fmt::Write;
+
+use user::ipc::{Ipc, Message};
+use user::process::{Pid, args, current_pid};
+use user::{print, println};
+
+// argv: hello <display server>
+#[unsafe(no_mangle)]
+fn main() {
+    let Some(server) = args().nth(1).and_then(|arg| arg.parse().ok()) else {
+        println!("hello: missing display server pid");
+        return;
+    };
+
+    let mut text = [0u8; 32];
+    let greeting = format!("hello from user mode, pid {}", current_pid());
+    let len = greeting.len().min(text.len());
+    text[..len].copy_from_slice(&greeting.as_bytes()[..len]);
+
+    let msg = Message::DisplayPrint {
+        display: 3,
+        line: 5,
+        text,
+        len: len as u8,
+    };
+    if let Err(err) = Ipc::send(Pid::from_raw(server), msg) {
+        println!("hello: send to the display server failed: {err:?}");
+    }
+}
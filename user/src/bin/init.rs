@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate alloc;
+
+use core::{fmt::Write, time::Duration};
+
+use user::process::{
+    Pid, Resource, SchedClass, Watchdog, set_affinity, set_limit, set_sched_class, set_watchdog,
+    spawn,
+};
+use user::{print, println};
+
+fn start(name: &str, argv: &[&str]) -> Option<Pid> {
+    match spawn(name, argv) {
+        Ok(pid) => Some(pid),
+        Err(errno) => {
+            println!("init: failed to start {name}: errno {errno}");
+            None
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+fn main() {
+    let Some(display) = start("display", &["display"]) else {
+        return;
+    };
+
+    // every app stalls behind a wedged display server, so start it over rather than wait
+    if let Err(errno) = set_watchdog(display, Watchdog::Restart) {
+        println!("init: failed to watch {display}: errno {errno}");
+    }
+
+    // the display server stays on hart 0, so its frames never wait for it to migrate
+    if let Err(errno) = set_affinity(display, 1 << 0) {
+        println!("init: failed to pin {display}: errno {errno}");
+    }
+
+    // the apps find the display server through their last argument
+    let server = format!("{}", display.as_raw());
+
+    let matrix = start("playground", &["matrix", "0", &server]);
+    let life = start("playground", &["life", "1", &server]);
+    let plasma = start("playground", &["plasma", "2", &server]);
+    let clock = start("playground", &["clock", "3", &server]);
+    let hello = start("hello", &["hello", &server]);
+
+    // none of the apps starts processes of its own
+    for pid in [matrix, life, plasma, clock, hello].into_iter().flatten() {
+        if let Err(errno) = set_limit(pid, Resource::Children, Some(0)) {
+            println!("init: failed to limit {pid}: errno {errno}");
+        }
+    }
+
+    // matrix draws a frame every 100 ms and must not miss one
+    let frame = SchedClass::RealTime {
+        period: Duration::from_millis(100),
+        budget: Duration::from_millis(20),
+        deadline: Duration::from_millis(100),
+    };
+    if let Some(pid) = matrix
+        && let Err(errno) = set_sched_class(pid, frame)
+    {
+        println!("init: failed to make {pid} real-time: errno {errno}");
+    }
+
+    // life gets twice the CPU time of plasma when both are busy
+    for (pid, weight) in [(life, 2048), (plasma, 1024)] {
+        if let Some(pid) = pid
+            && let Err(errno) = set_sched_class(pid, SchedClass::Fair { weight })
+        {
+            println!("init: failed to set the weight of {pid}: errno {errno}");
+        }
+    }
+}
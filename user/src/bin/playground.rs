@@ -0,0 +1,348 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use user::ipc::{Ipc, Message};
+use user::process::{Pid, args};
+use user::timer::{Instant, sleep_until};
+use user::{futex, thread};
+use user::{print, println};
+
+// raw pid of the display server, set from the command line before any app runs
+static DISPLAY_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+fn display_server() -> Pid {
+    Pid::from_raw(DISPLAY_SERVER.load(Ordering::Relaxed))
+}
+
+const FRAME_TIME: Duration = Duration::from_millis(100);
+
+fn send_print(display: u8, line: u8, text: &str) {
+    let mut buf = [0u8; 32];
+    let bytes = text.as_bytes();
+    let len = bytes.len().min(32);
+    buf[..len].copy_from_slice(&bytes[..len]);
+    let _ = Ipc::send(
+        display_server(),
+        Message::DisplayPrint {
+            display,
+            line,
+            text: buf,
+            len: len as u8,
+        },
+    );
+}
+
+fn send_draw_cell(display: u8, x: u8, y: u8, fg: u8, bg: u8, ch: char) {
+    let _ = Ipc::send(
+        display_server(),
+        Message::DisplayDrawCell {
+            display,
+            x,
+            y,
+            fg,
+            bg,
+            ch,
+        },
+    );
+}
+
+fn send_clear(display: u8) {
+    let _ = Ipc::send(display_server(), Message::DisplayClear(display));
+}
+
+fn lfsr_next(state: &mut u32) -> u8 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    (x & 0xFF) as u8
+}
+
+fn matrix(display: u8) -> ! {
+    send_clear(display);
+    send_print(display, 0, "Matrix");
+
+    const WIDTH: usize = 80;
+    const HEIGHT: i8 = 20;
+    let mut heads: [i8; WIDTH] = [-1; WIDTH];
+    let mut lengths: [u8; WIDTH] = [0; WIDTH];
+    let mut seed: u32 = 0x1234_5678;
+    let mut next_frame = Instant::now();
+
+    loop {
+        for col in 0..WIDTH {
+            if heads[col] < 0 {
+                if (lfsr_next(&mut seed) & 7) == 0 {
+                    heads[col] = 0;
+                    lengths[col] = 3 + (lfsr_next(&mut seed) % 4);
+                }
+            } else {
+                let head = heads[col];
+                let len = lengths[col] as i8;
+
+                let ch = {
+                    let r = lfsr_next(&mut seed);
+                    let idx = r % 36;
+                    if idx < 10 {
+                        (b'0' + idx) as char
+                    } else {
+                        (b'A' + (idx - 10)) as char
+                    }
+                };
+                if head < HEIGHT {
+                    send_draw_cell(display, col as u8, head as u8, 10, 0, ch);
+                }
+
+                for t in 1..len {
+                    let y = head - t;
+                    if (0..HEIGHT).contains(&y) {
+                        send_draw_cell(display, col as u8, y as u8, 2, 0, ch);
+                    }
+                }
+
+                if head - len >= 0 && head - len < HEIGHT {
+                    send_draw_cell(display, col as u8, (head - len) as u8, 0, 0, ' ');
+                }
+
+                heads[col] += 1;
+                if heads[col] - len > HEIGHT {
+                    heads[col] = -1;
+                }
+            }
+        }
+
+        next_frame += FRAME_TIME;
+        sleep_until(next_frame);
+    }
+}
+
+const LIFE_W: usize = 80;
+const LIFE_H: usize = 20;
+const LIFE_SIZE: usize = LIFE_W * LIFE_H;
+
+// Two boards: while one generation is drawn, a worker thread computes the next into the other
+static BOARDS: [[AtomicU8; LIFE_SIZE]; 2] = [const { [const { AtomicU8::new(0) }; LIFE_SIZE] }; 2];
+// generation the worker may compute up to, and the last one it has finished
+static REQUESTED: AtomicUsize = AtomicUsize::new(0);
+static COMPUTED: AtomicUsize = AtomicUsize::new(0);
+
+// Each counter has a single writer, which wakes the other thread after every store
+fn wait_for_generation(counter: &AtomicUsize, generation: usize) {
+    loop {
+        let seen = counter.load(Ordering::Acquire);
+        if seen >= generation {
+            return;
+        }
+        let _ = futex::wait(counter, seen, None);
+    }
+}
+
+fn set_generation(counter: &AtomicUsize, generation: usize) {
+    counter.store(generation, Ordering::Release);
+    futex::wake(counter, 1);
+}
+
+fn life_step(cur: &[AtomicU8; LIFE_SIZE], next: &[AtomicU8; LIFE_SIZE]) {
+    for y in 0..LIFE_H {
+        for x in 0..LIFE_W {
+            let mut neighbors = 0;
+            for dy in [-1isize, 0, 1] {
+                for dx in [-1isize, 0, 1] {
+                    if dx == 0 && dy == 0 {
+                        continue;
+                    }
+                    let nx = ((x as isize + dx + LIFE_W as isize) % LIFE_W as isize) as usize;
+                    let ny = ((y as isize + dy + LIFE_H as isize) % LIFE_H as isize) as usize;
+                    if cur[ny * LIFE_W + nx].load(Ordering::Relaxed) != 0 {
+                        neighbors += 1;
+                    }
+                }
+            }
+            let idx = y * LIFE_W + x;
+            let alive = match (cur[idx].load(Ordering::Relaxed), neighbors) {
+                (1, 2) | (1, 3) => 1,
+                (0, 3) => 1,
+                _ => 0,
+            };
+            next[idx].store(alive, Ordering::Relaxed);
+        }
+    }
+}
+
+fn life_worker() {
+    let mut generation = 0;
+    loop {
+        wait_for_generation(&REQUESTED, generation + 1);
+        life_step(&BOARDS[generation % 2], &BOARDS[(generation + 1) % 2]);
+        generation += 1;
+        set_generation(&COMPUTED, generation);
+    }
+}
+
+fn life(display: u8) -> ! {
+    send_clear(display);
+    send_print(display, 0, "Game of Life");
+
+    let cur = &BOARDS[0];
+    for idx in [
+        LIFE_W + 2,
+        2 * LIFE_W + 3,
+        3 * LIFE_W + 1,
+        3 * LIFE_W + 2,
+        3 * LIFE_W + 3,
+    ] {
+        cur[idx].store(1, Ordering::Relaxed);
+    }
+    let bx = 10;
+    let by = 2;
+    for y in by..by + 3 {
+        cur[y * LIFE_W + bx + 1].store(1, Ordering::Relaxed);
+    }
+
+    if let Err(errno) = thread::spawn(life_worker) {
+        panic!("life: failed to start the worker thread: {errno}");
+    }
+
+    let mut generation = 0;
+    let mut next_frame = Instant::now();
+    loop {
+        set_generation(&REQUESTED, generation + 1);
+
+        let cur = &BOARDS[generation % 2];
+        for y in 0..LIFE_H {
+            for x in 0..LIFE_W {
+                if cur[y * LIFE_W + x].load(Ordering::Relaxed) != 0 {
+                    send_draw_cell(display, x as u8, (y + 1) as u8, 2, 0, '■');
+                } else {
+                    send_draw_cell(display, x as u8, (y + 1) as u8, 0, 0, ' ');
+                }
+            }
+        }
+
+        wait_for_generation(&COMPUTED, generation + 1);
+        generation += 1;
+
+        next_frame += FRAME_TIME;
+        sleep_until(next_frame);
+    }
+}
+
+fn plasma(display: u8) -> ! {
+    send_clear(display);
+    send_print(display, 0, "Plasma effect");
+
+    let mut t: u8 = 0;
+    let mut next_frame = Instant::now();
+    loop {
+        for y in 0..20 {
+            for x in 0..80 {
+                let v = ((x as u8)
+                    .wrapping_mul(3)
+                    .wrapping_add((y as u8).wrapping_mul(5))
+                    .wrapping_add(t.wrapping_mul(2)))
+                    & 7;
+                let bg = 1 + v;
+                send_draw_cell(display, x as u8, (y + 1) as u8, 0, bg, ' ');
+            }
+        }
+
+        t = t.wrapping_add(1);
+
+        next_frame += FRAME_TIME;
+        sleep_until(next_frame);
+    }
+}
+
+fn clock(display: u8) -> ! {
+    send_clear(display);
+    send_print(display, 0, "Clock + heartbeat");
+
+    let mut seconds: u32 = 0;
+    let mut seed: u32 = 0xdead_beef;
+    const HEARTBEAT_PERIOD: u32 = 8;
+    let mut next_tick = Instant::now();
+
+    fn xorshift32(state: &mut u32) -> u8 {
+        let mut x = *state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *state = x;
+        (x & 0xFF) as u8
+    }
+
+    loop {
+        let total = seconds % 86400;
+        let hour = (total / 3600) as u8;
+        let minute = ((total % 3600) / 60) as u8;
+        let sec = (total % 60) as u8;
+
+        let mut buf = [b'0'; 8];
+        fn two_digits(out: &mut [u8], v: u8) {
+            out[0] = b'0' + (v / 10);
+            out[1] = b'0' + (v % 10);
+        }
+        two_digits(&mut buf[0..2], hour);
+        buf[2] = b':';
+        two_digits(&mut buf[3..5], minute);
+        buf[5] = b':';
+        two_digits(&mut buf[6..8], sec);
+
+        let mut display_str = alloc::string::String::new();
+        if (seconds % 30) == 0 && (xorshift32(&mut seed) & 3) == 0 {
+            for &c in buf.iter().rev() {
+                if (xorshift32(&mut seed) & 7) == 0 {
+                    display_str.push('?');
+                } else {
+                    display_str.push(c as char);
+                }
+            }
+        } else {
+            for &c in buf.iter() {
+                display_str.push(c as char);
+            }
+        }
+
+        send_print(display, 1, &display_str);
+
+        let beat_on = (seconds % HEARTBEAT_PERIOD) < 4;
+        let heart_char = if beat_on { '♥' } else { ' ' };
+        let color = if beat_on { 9 } else { 8 };
+        send_draw_cell(display, 0, 3, color, 0, heart_char);
+
+        seconds = seconds.wrapping_add(1);
+
+        next_tick += Duration::from_secs(1);
+        sleep_until(next_tick);
+    }
+}
+
+// Started under the name of the app to run: <matrix|life|plasma|clock> <display> <display server>
+#[unsafe(no_mangle)]
+fn main() {
+    let mut args = args();
+    let app = args.next().unwrap_or("");
+    let display = args.next().and_then(|arg| arg.parse().ok()).unwrap_or(0);
+    let Some(server) = args.next().and_then(|arg| arg.parse().ok()) else {
+        println!("{app}: missing display server pid");
+        return;
+    };
+    DISPLAY_SERVER.store(server, Ordering::Relaxed);
+
+    match app {
+        "matrix" => matrix(display),
+        "life" => life(display),
+        "plasma" => plasma(display),
+        "clock" => clock(display),
+        _ => println!("playground: unknown app {app:?}"),
+    }
+}
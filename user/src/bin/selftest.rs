@@ -0,0 +1,334 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use abi::{EAGAIN, EINVAL, ENOMEM, EPERM, ESRCH, ETIMEDOUT};
+use user::ipc::{Ipc, IpcError, Message, Src};
+use user::process::{
+    Pid, Resource, args, checkpoint, current_pid, limit, restore, set_affinity, set_limit, spawn,
+};
+use user::timer::{Instant, sleep};
+use user::{futex, shm, thread};
+use user::{print, println};
+
+const PAGE_SIZE: usize = 4096;
+
+// shared memory keys of the checks
+const SHM_KEY: usize = 0x5e1f_0001;
+const LIMITED_KEY: usize = 0x5e1f_0002;
+
+macro_rules! ensure {
+    ($cond:expr, $($msg:tt)*) => {
+        if !$cond {
+            return Err(format!($($msg)*));
+        }
+    };
+}
+
+type Check = fn() -> Result<(), String>;
+
+// `limits` lowers the limits of this process for good, so it goes last
+const CHECKS: &[(&str, Check)] = &[
+    ("pid reuse", pid_reuse),
+    ("ipc with a dead pid", ipc_dead_pid),
+    ("timer order", timer_order),
+    ("checkpoint round trip", checkpoint_round_trip),
+    ("futex wait and wake", futex_wait_wake),
+    ("affinity", affinity),
+    ("shared memory", shared_memory),
+    ("limits", limits),
+];
+
+fn errno(errno: isize) -> String {
+    format!("errno {errno}")
+}
+
+fn start(argv: &[&str]) -> Result<Pid, String> {
+    spawn("selftest", argv).map_err(|errno| format!("failed to start {argv:?}: errno {errno}"))
+}
+
+// There is no wait syscall: a child is gone once its limits can no longer be read
+fn wait_gone(pid: Pid) -> Result<(), String> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while limit(pid, Resource::Pages) != Err(ESRCH) {
+        ensure!(
+            Instant::now() < deadline,
+            "{pid} still there after a second"
+        );
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+fn dead_child() -> Result<Pid, String> {
+    let pid = start(&["selftest", "exit"])?;
+    wait_gone(pid)?;
+    Ok(pid)
+}
+
+fn echo_child() -> Result<Pid, String> {
+    let me = format!("{}", current_pid().as_raw());
+    start(&["selftest", "echo", &me])
+}
+
+// Has a child started with "echo" answer, returning how many messages it answered so far
+fn ask(echo: Pid) -> Result<usize, String> {
+    Ipc::send(echo, Message::Data { a: 0, b: 0 })
+        .map_err(|err| format!("send to {echo}: {err:?}"))?;
+    match Ipc::recv(Src::Specific(echo)) {
+        Ok(Message::Data { b, .. }) => Ok(b),
+        other => Err(format!("{echo} answered {other:?}")),
+    }
+}
+
+fn stop(echo: Pid) -> Result<(), String> {
+    Ipc::send(echo, Message::Ping).map_err(|err| format!("send to {echo}: {err:?}"))?;
+    wait_gone(echo)
+}
+
+// A slot taken over by a new process gets a new generation, and the old pid reaches nobody
+fn pid_reuse() -> Result<(), String> {
+    let old = dead_child()?;
+    let new = echo_child()?;
+    ensure!(new != old, "{old} handed out again");
+    ensure!(
+        limit(old, Resource::Pages) == Err(ESRCH),
+        "stale {old} reaches {new}"
+    );
+    ensure!(
+        matches!(Ipc::send(old, Message::Ping), Err(IpcError::NoSuchProcess)),
+        "a message to stale {old} went through"
+    );
+    ensure!(ask(new)? == 1, "{new} answered for {old}");
+    stop(new)
+}
+
+fn ipc_dead_pid() -> Result<(), String> {
+    let dead = dead_child()?;
+    let sent = Ipc::send(dead, Message::Ping);
+    ensure!(
+        matches!(sent, Err(IpcError::NoSuchProcess)),
+        "send to {dead}: {sent:?}"
+    );
+    let received = Ipc::recv(Src::Specific(dead));
+    ensure!(
+        matches!(received, Err(IpcError::NoSuchProcess)),
+        "recv from {dead}: {received:?}"
+    );
+    Ok(())
+}
+
+// Threads wake up in the order of their deadlines, not of their sleeps
+fn timer_order() -> Result<(), String> {
+    let start = Instant::now();
+    let sleepers = [30, 10, 20].map(|ms| {
+        thread::spawn(move || {
+            sleep(Duration::from_millis(ms));
+            thread::exit(start.elapsed().as_micros() as usize);
+        })
+    });
+    let mut woke = Vec::new();
+    for sleeper in sleepers {
+        woke.push(sleeper.map_err(errno)?.join().map_err(errno)?);
+    }
+
+    let [slow, fast, middle] = woke[..] else {
+        unreachable!()
+    };
+    ensure!(
+        fast >= 10_000 && middle >= 20_000 && slow >= 30_000,
+        "woke up early, after {woke:?} us"
+    );
+    ensure!(
+        fast < middle && middle < slow,
+        "woke up out of order, after {woke:?} us"
+    );
+    Ok(())
+}
+
+// The copy picks up where the original was, blocked in recv with the count it had
+fn checkpoint_round_trip() -> Result<(), String> {
+    let original = echo_child()?;
+    ensure!(ask(original)? == 1, "{original} miscounted");
+
+    // the child may not be back in recv yet
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let blob = loop {
+        match checkpoint(original) {
+            Err(EAGAIN) if Instant::now() < deadline => sleep(Duration::from_millis(1)),
+            blob => break blob.map_err(errno)?,
+        }
+    };
+    let copy = restore(&blob).map_err(errno)?;
+    ensure!(copy != original, "restored as {original} itself");
+
+    ensure!(
+        ask(copy)? == 2,
+        "{copy} did not keep the count of {original}"
+    );
+    ensure!(ask(original)? == 2, "{original} changed by the checkpoint");
+    stop(copy)?;
+    stop(original)
+}
+
+fn futex_wait_wake() -> Result<(), String> {
+    static WORD: AtomicUsize = AtomicUsize::new(0);
+
+    let mismatch = futex::wait(&WORD, 1, None);
+    ensure!(
+        mismatch == Err(EAGAIN),
+        "waiting on a value the word does not hold: {mismatch:?}"
+    );
+
+    let start = Instant::now();
+    let timed_out = futex::wait(&WORD, 0, Some(Duration::from_millis(10)));
+    ensure!(timed_out == Err(ETIMEDOUT), "timed wait: {timed_out:?}");
+    ensure!(
+        start.elapsed() >= Duration::from_millis(10),
+        "timed out after {:?}",
+        start.elapsed()
+    );
+
+    let waiter = thread::spawn(|| {
+        while WORD.load(Ordering::Acquire) == 0 {
+            let _ = futex::wait(&WORD, 0, None);
+        }
+    })
+    .map_err(errno)?;
+    // long enough for the waiter to be asleep on the word
+    sleep(Duration::from_millis(20));
+    WORD.store(1, Ordering::Release);
+    let woken = futex::wake(&WORD, 1);
+    waiter.join().map_err(errno)?;
+    ensure!(woken == 1, "woke {woken} waiters instead of 1");
+    ensure!(futex::wake(&WORD, 1) == 0, "woke a waiter twice");
+    Ok(())
+}
+
+// A mask needs a hart that is up, and only the process itself or its parent may set it
+fn affinity() -> Result<(), String> {
+    let me = current_pid();
+    ensure!(set_affinity(me, 0) == Err(EINVAL), "accepted an empty mask");
+    ensure!(
+        set_affinity(me, 1 << (usize::BITS - 1)) == Err(EINVAL),
+        "accepted a mask of harts that do not exist"
+    );
+
+    set_affinity(me, 1 << 0).map_err(errno)?;
+    sleep(Duration::from_millis(1));
+    set_affinity(me, usize::MAX).map_err(errno)?;
+
+    let dead = dead_child()?;
+    ensure!(
+        set_affinity(dead, 1 << 0) == Err(ESRCH),
+        "pinned dead {dead}"
+    );
+    Ok(())
+}
+
+// Attaching a key again maps the same pages elsewhere, and a detached range is reused first
+fn shared_memory() -> Result<(), String> {
+    let first = shm::attach(SHM_KEY, PAGE_SIZE).map_err(errno)?;
+    let second = shm::attach(SHM_KEY, PAGE_SIZE).map_err(errno)?;
+    ensure!(first != second, "attached twice at {first:?}");
+    unsafe { first.write(42) };
+    ensure!(unsafe { second.read() } == 42, "the mappings differ");
+    ensure!(
+        shm::attach(SHM_KEY, 2 * PAGE_SIZE) == Err(EINVAL),
+        "attached the segment with another size"
+    );
+
+    shm::detach(first).map_err(errno)?;
+    let third = shm::attach(SHM_KEY, PAGE_SIZE).map_err(errno)?;
+    ensure!(
+        third == first,
+        "attached at {third:?} instead of the hole at {first:?}"
+    );
+    ensure!(
+        unsafe { third.read() } == 42,
+        "the segment lost its contents"
+    );
+
+    shm::detach(second).map_err(errno)?;
+    shm::detach(third).map_err(errno)?;
+    ensure!(shm::detach(third) == Err(EINVAL), "detached twice");
+    Ok(())
+}
+
+// Limits hold, and only ever go down
+fn limits() -> Result<(), String> {
+    let me = current_pid();
+    set_limit(me, Resource::SharedMemory, Some(1)).map_err(errno)?;
+    let shm_limit = limit(me, Resource::SharedMemory);
+    ensure!(shm_limit == Ok(Some(1)), "limit read back as {shm_limit:?}");
+    ensure!(
+        shm::attach(LIMITED_KEY, 2 * PAGE_SIZE) == Err(ENOMEM),
+        "attached 2 pages past a limit of 1"
+    );
+    let page = shm::attach(LIMITED_KEY, PAGE_SIZE).map_err(errno)?;
+    ensure!(
+        shm::attach(LIMITED_KEY, PAGE_SIZE) == Err(ENOMEM),
+        "attached a page twice with a limit of 1"
+    );
+    shm::detach(page).map_err(errno)?;
+    ensure!(
+        set_limit(me, Resource::SharedMemory, None) == Err(EPERM),
+        "raised its own limit"
+    );
+
+    set_limit(me, Resource::Children, Some(0)).map_err(errno)?;
+    ensure!(
+        spawn("selftest", &["selftest", "exit"]) == Err(EAGAIN),
+        "started a child past a limit of 0"
+    );
+    Ok(())
+}
+
+// Answers each Data message with how many it has answered so far, until a Ping
+fn echo(parent: Pid) {
+    let mut answered = 0;
+    while let Ok(Message::Data { a, .. }) = Ipc::recv(Src::Any) {
+        answered += 1;
+        let _ = Ipc::send(parent, Message::Data { a, b: answered });
+    }
+}
+
+// argv: selftest, or selftest exit | echo <parent> for the children the checks start
+#[unsafe(no_mangle)]
+fn main() {
+    let mut argv = args().skip(1);
+    match (argv.next(), argv.next().and_then(|arg| arg.parse().ok())) {
+        (None, _) => {}
+        (Some("exit"), _) => return,
+        (Some("echo"), Some(parent)) => return echo(Pid::from_raw(parent)),
+        (Some(mode), _) => {
+            println!("selftest: bad arguments for {mode}");
+            return;
+        }
+    }
+
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => println!("selftest: {name} ok"),
+            Err(err) => {
+                println!("selftest: {name} FAILED: {err}");
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "selftest: {} of {} checks passed",
+        CHECKS.len() - failed,
+        CHECKS.len()
+    );
+}
@@ -0,0 +1,31 @@
+use core::fmt;
+
+use abi::SYS_WRITE;
+
+use crate::syscall::syscall;
+
+pub fn write(bytes: &[u8]) -> Result<usize, isize> {
+    syscall(SYS_WRITE, &[bytes.as_ptr() as usize, bytes.len()])
+}
+
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s.as_bytes()).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ({
+        let _ = write!($crate::console::Writer, $($arg)*);
+    });
+}
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => ({
+        print!("{}\n", format_args!($($arg)*));
+    });
+}
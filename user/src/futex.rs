@@ -0,0 +1,23 @@
+use core::{sync::atomic::AtomicUsize, time::Duration};
+
+use abi::{FUTEX_WAIT, FUTEX_WAKE, SYS_FUTEX};
+
+use crate::syscall::syscall;
+
+// Sleeps while `word` holds `expected`, until a `wake` on it or the timeout runs out (ETIMEDOUT).
+// Fails with EAGAIN right away if the word holds something else. Wake-ups may be spurious, so
+// callers check the word again.
+pub fn wait(word: &AtomicUsize, expected: usize, timeout: Option<Duration>) -> Result<(), isize> {
+    // 0 means no timeout, so round anything shorter than a microsecond up
+    let timeout = timeout.map_or(0, |timeout| timeout.as_micros().max(1) as usize);
+    syscall(
+        SYS_FUTEX,
+        &[word.as_ptr() as usize, FUTEX_WAIT, expected, timeout],
+    )
+    .map(|_| ())
+}
+
+// Wakes up to `count` threads waiting on `word`; returns how many were woken
+pub fn wake(word: &AtomicUsize, count: usize) -> usize {
+    syscall(SYS_FUTEX, &[word.as_ptr() as usize, FUTEX_WAKE, count]).unwrap_or(0)
+}
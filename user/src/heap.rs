@@ -0,0 +1,192 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::AtomicUsize,
+};
+
+use abi::{SYS_BRK, SYS_SBRK};
+
+use crate::{futex, syscall::syscall};
+
+// the heap grows by at least this much at a time
+const CHUNK_SIZE: usize = 4096;
+
+// every block is a multiple of this, large enough to hold a `FreeBlock` once freed
+const BLOCK_ALIGN: usize = 8;
+
+// Moves the program break, 0 queries it
+pub fn brk(addr: usize) -> Result<usize, isize> {
+    syscall(SYS_BRK, &[addr])
+}
+
+// Moves the program break by `increment` bytes and returns the old one
+pub fn sbrk(increment: usize) -> Result<usize, isize> {
+    syscall(SYS_SBRK, &[increment])
+}
+
+// The target has no A extension, but the harts QEMU provides do
+fn swap(word: &AtomicUsize, value: usize) -> usize {
+    let old: usize;
+    unsafe {
+        asm!(
+            ".option push",
+            ".option arch, +a",
+            "amoswap.w.aqrl {old}, {value}, ({addr})",
+            ".option pop",
+            old = out(reg) old,
+            value = in(reg) value,
+            addr = in(reg) word.as_ptr(),
+        );
+    }
+    old
+}
+
+// 0 when free, 1 when taken, 2 when taken and someone may sleep on it. Only swaps are available,
+// so a waiter always leaves 2 behind and an unlock may wake nobody.
+struct HeapLock(AtomicUsize);
+
+impl HeapLock {
+    fn lock(&self) {
+        if swap(&self.0, 1) == 0 {
+            return;
+        }
+        while swap(&self.0, 2) != 0 {
+            let _ = futex::wait(&self.0, 2, None);
+        }
+    }
+
+    fn unlock(&self) {
+        if swap(&self.0, 0) == 2 {
+            futex::wake(&self.0, 1);
+        }
+    }
+}
+
+// Kept in the first bytes of each free block
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+// First-fit allocator over the brk heap. Free blocks are kept in a list sorted by address and
+// merged with their neighbours; when none fits, the break moves up by whole chunks.
+struct Allocator {
+    lock: HeapLock,
+    free: UnsafeCell<*mut FreeBlock>,
+}
+
+// the free list is only touched with the lock held
+unsafe impl Sync for Allocator {}
+
+impl Allocator {
+    // Carves `size` bytes aligned to `align` out of a free block, None if no block fits
+    unsafe fn take(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut link = self.free.get();
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let start = block as usize;
+                let end = start + (*block).size;
+                let addr = start.next_multiple_of(align);
+                if addr + size <= end {
+                    *link = (*block).next;
+                    // what is left on either side goes back, each part still a whole block
+                    self.give(start, addr - start);
+                    self.give(addr + size, end - addr - size);
+                    return Some(addr as *mut u8);
+                }
+                link = &raw mut (*block).next;
+            }
+        }
+        None
+    }
+
+    // Puts `size` bytes at `addr` on the free list, merging them with adjacent free blocks
+    unsafe fn give(&self, addr: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut link = self.free.get();
+        unsafe {
+            while !(*link).is_null() && (*link as usize) < addr {
+                prev = *link;
+                link = &raw mut (*prev).next;
+            }
+
+            let next = *link;
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            *link = block;
+
+            if !next.is_null() && addr + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            }
+        }
+    }
+
+    // Moves the break up by enough whole chunks for `size` bytes aligned to `align`
+    unsafe fn grow(&self, size: usize, align: usize) -> bool {
+        let Some(len) = size
+            .checked_add(align)
+            .and_then(|len| len.checked_next_multiple_of(CHUNK_SIZE))
+        else {
+            return false;
+        };
+        match sbrk(len) {
+            Ok(start) => {
+                // the break is only unaligned if someone moved it by hand
+                let end = start + len;
+                let start = start.next_multiple_of(BLOCK_ALIGN);
+                unsafe { self.give(start, (end - start) & !(BLOCK_ALIGN - 1)) };
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+// Allocations are rounded up to whole blocks, and freed with the same layout they were made with
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = layout.size().max(1).next_multiple_of(BLOCK_ALIGN);
+    (size, layout.align().max(BLOCK_ALIGN))
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+
+        self.lock.lock();
+        let ptr = unsafe {
+            match self.take(size, align) {
+                Some(ptr) => ptr,
+                None if self.grow(size, align) => self.take(size, align).unwrap_or(ptr::null_mut()),
+                None => ptr::null_mut(),
+            }
+        };
+        self.lock.unlock();
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+
+        self.lock.lock();
+        unsafe { self.give(ptr as usize, size) };
+        self.lock.unlock();
+    }
+}
+
+#[global_allocator]
+static HEAP: Allocator = Allocator {
+    lock: HeapLock(AtomicUsize::new(0)),
+    free: UnsafeCell::new(ptr::null_mut()),
+};
@@ -0,0 +1,56 @@
+pub use abi::Message;
+
+use abi::{EAGAIN, EBUSY, EDEADLK, EINVAL, ESRCH, MESSAGE_WORDS, SRC_ANY, SYS_RECV, SYS_SEND};
+
+use crate::{process::Pid, syscall::syscall};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Src {
+    Specific(Pid),
+    Any,
+}
+
+#[derive(Debug)]
+pub enum IpcError {
+    SelfSend,
+    DeadlockDetected,
+    SendQueueFull,
+    NoSuchProcess,
+    UnexpectedState,
+    // another thread of the process is blocked in send or recv
+    Busy,
+}
+
+impl IpcError {
+    fn from_errno(errno: isize) -> Self {
+        match errno {
+            EINVAL => IpcError::SelfSend,
+            EDEADLK => IpcError::DeadlockDetected,
+            EAGAIN => IpcError::SendQueueFull,
+            ESRCH => IpcError::NoSuchProcess,
+            EBUSY => IpcError::Busy,
+            _ => IpcError::UnexpectedState,
+        }
+    }
+}
+
+pub struct Ipc;
+
+impl Ipc {
+    pub fn send(dst: Pid, msg: Message) -> Result<(), IpcError> {
+        let words = msg.encode();
+        syscall(SYS_SEND, &[dst.as_raw(), words.as_ptr() as usize])
+            .map(|_| ())
+            .map_err(IpcError::from_errno)
+    }
+
+    pub fn recv(src: Src) -> Result<Message, IpcError> {
+        let src = match src {
+            Src::Specific(pid) => pid.as_raw(),
+            Src::Any => SRC_ANY,
+        };
+        let mut words = [0; MESSAGE_WORDS];
+        syscall(SYS_RECV, &[src, words.as_mut_ptr() as usize]).map_err(IpcError::from_errno)?;
+        Message::decode(&words).ok_or(IpcError::UnexpectedState)
+    }
+}
@@ -0,0 +1,19 @@
+//! Runtime for user programs: startup, system call wrappers, console output, a heap, threads,
+//! futexes and shared memory.
+//!
+//! A program links against this crate and defines its entry point as
+//! `#[unsafe(no_mangle)] fn main()`; returning from it exits the process.
+#![no_std]
+
+extern crate alloc;
+
+pub mod console;
+pub mod futex;
+pub mod heap;
+pub mod ipc;
+pub mod process;
+pub mod shm;
+mod start;
+pub mod syscall;
+pub mod thread;
+pub mod timer;
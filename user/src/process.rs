@@ -0,0 +1,244 @@
+use alloc::{vec, vec::Vec};
+use core::{cell::Cell, ffi::CStr, fmt, ptr, time::Duration};
+
+use abi::{
+    ERANGE, PID_INDEX_BITS, RLIM_INFINITY, RLIMIT_CHILDREN, RLIMIT_CPU, RLIMIT_IPC_QUEUE,
+    RLIMIT_PAGES, RLIMIT_SHM, SCHED_EDF, SCHED_FAIR, SCHED_MLFQ, SYS_CHECKPOINT, SYS_EXEC,
+    SYS_EXIT, SYS_GETPID, SYS_GETRLIMIT, SYS_RESTORE, SYS_SCHED_SET, SYS_SET_AFFINITY,
+    SYS_SETRLIMIT, SYS_SPAWN, SYS_WATCHDOG, SYS_YIELD, WATCHDOG_KILL, WATCHDOG_REPORT,
+    WATCHDOG_RESTART,
+};
+
+use crate::syscall::syscall;
+
+// Same layout as the kernel's Pid, see PID_INDEX_BITS
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Pid {
+    index: usize,
+    generation: usize,
+}
+
+impl Pid {
+    pub const fn new(index: usize, generation: usize) -> Self {
+        Pid { index, generation }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    pub fn as_raw(&self) -> usize {
+        self.index | self.generation << PID_INDEX_BITS
+    }
+
+    pub fn from_raw(raw: usize) -> Self {
+        Pid::new(raw & ((1 << PID_INDEX_BITS) - 1), raw >> PID_INDEX_BITS)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+pub fn exit(code: usize) -> ! {
+    let _ = syscall(SYS_EXIT, &[code]);
+    unreachable!();
+}
+
+pub fn yield_now() {
+    let _ = syscall(SYS_YIELD, &[]);
+}
+
+pub fn current_pid() -> Pid {
+    Pid::from_raw(syscall(SYS_GETPID, &[]).unwrap_or(0))
+}
+
+// Starts the program `name` in a new process; argv[0] is conventionally the program name
+pub fn spawn(name: &str, argv: &[&str]) -> Result<Pid, isize> {
+    command(SYS_SPAWN, name, argv).map(Pid::from_raw)
+}
+
+// Replaces the current program with `name`; only returns, with the errno, on failure
+pub fn exec(name: &str, argv: &[&str]) -> isize {
+    match command(SYS_EXEC, name, argv) {
+        Ok(_) => unreachable!(),
+        Err(errno) => errno,
+    }
+}
+
+fn command(number: usize, name: &str, argv: &[&str]) -> Result<usize, isize> {
+    let pairs: Vec<[usize; 2]> = argv
+        .iter()
+        .map(|arg| [arg.as_ptr() as usize, arg.len()])
+        .collect();
+    syscall(
+        number,
+        &[
+            name.as_ptr() as usize,
+            name.len(),
+            pairs.as_ptr() as usize,
+            pairs.len(),
+        ],
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchedClass {
+    // runs before everything else, earliest deadline first, for up to `budget` every `period`;
+    // the kernel refuses reservations it cannot guarantee and those taking more than a fixed
+    // share of the CPU for one process
+    RealTime {
+        period: Duration,
+        budget: Duration,
+        deadline: Duration,
+    },
+    // 0 is the highest priority level
+    Mlfq {
+        priority: usize,
+    },
+    // gets a fixed share of the CPU next to MLFQ processes, or all they leave, split in
+    // proportion to the weights
+    Fair {
+        weight: usize,
+    },
+}
+
+// Changes the scheduling class of the calling process or one of its children
+pub fn set_sched_class(pid: Pid, class: SchedClass) -> Result<(), isize> {
+    let micros = |duration: Duration| duration.as_micros() as usize;
+    let args = match class {
+        SchedClass::RealTime {
+            period,
+            budget,
+            deadline,
+        } => [SCHED_EDF, micros(period), micros(budget), micros(deadline)],
+        SchedClass::Mlfq { priority } => [SCHED_MLFQ, priority, 0, 0],
+        SchedClass::Fair { weight } => [SCHED_FAIR, weight, 0, 0],
+    };
+    syscall(
+        SYS_SCHED_SET,
+        &[pid.as_raw(), args[0], args[1], args[2], args[3]],
+    )
+    .map(|_| ())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Resource {
+    // user pages: image, stack and heap
+    Pages,
+    // in microseconds; the process is killed once it runs past it
+    CpuTime,
+    Children,
+    // messages that may queue up for the process
+    IpcQueue,
+    // pages of shared memory attached at a time
+    SharedMemory,
+}
+
+impl Resource {
+    fn number(&self) -> usize {
+        match self {
+            Resource::Pages => RLIMIT_PAGES,
+            Resource::CpuTime => RLIMIT_CPU,
+            Resource::Children => RLIMIT_CHILDREN,
+            Resource::IpcQueue => RLIMIT_IPC_QUEUE,
+            Resource::SharedMemory => RLIMIT_SHM,
+        }
+    }
+}
+
+// None means unlimited
+pub fn limit(pid: Pid, resource: Resource) -> Result<Option<usize>, isize> {
+    let value = syscall(SYS_GETRLIMIT, &[pid.as_raw(), resource.number()])?;
+    Ok((value != RLIM_INFINITY).then_some(value))
+}
+
+// Sets a limit of the calling process or one of its children; limits can only be lowered
+pub fn set_limit(pid: Pid, resource: Resource, limit: Option<usize>) -> Result<(), isize> {
+    let value = limit.unwrap_or(RLIM_INFINITY);
+    syscall(SYS_SETRLIMIT, &[pid.as_raw(), resource.number(), value]).map(|_| ())
+}
+
+// What the kernel watchdog does to a process that stops making progress. It always reports it,
+// along with the processes it waits for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Watchdog {
+    Report,
+    Kill,
+    // start the program over under the same pid, so that its clients can keep talking to it
+    Restart,
+}
+
+pub fn set_watchdog(pid: Pid, action: Watchdog) -> Result<(), isize> {
+    let action = match action {
+        Watchdog::Report => WATCHDOG_REPORT,
+        Watchdog::Kill => WATCHDOG_KILL,
+        Watchdog::Restart => WATCHDOG_RESTART,
+    };
+    syscall(SYS_WATCHDOG, &[pid.as_raw(), action]).map(|_| ())
+}
+
+// Restricts the calling process or one of its children to the harts whose bits are set in
+// `mask`, bit 0 for hart 0. At least one of them has to be up.
+pub fn set_affinity(pid: Pid, mask: usize) -> Result<(), isize> {
+    syscall(SYS_SET_AFFINITY, &[pid.as_raw(), mask]).map(|_| ())
+}
+
+// Writes a child process out as a blob that `restore` turns into a new child, within this boot
+// or under another instance of the same kernel. The copy gets a new pid and new thread ids, and
+// a thread caught inside a blocking call makes the call again.
+pub fn checkpoint(pid: Pid) -> Result<Vec<u8>, isize> {
+    loop {
+        let size = syscall(SYS_CHECKPOINT, &[pid.as_raw(), 0, 0])?;
+        let mut blob = vec![0u8; size];
+        // the child may have grown since it was measured
+        match syscall(
+            SYS_CHECKPOINT,
+            &[pid.as_raw(), blob.as_mut_ptr() as usize, size],
+        ) {
+            Ok(size) => {
+                blob.truncate(size);
+                return Ok(blob);
+            }
+            Err(ERANGE) => continue,
+            Err(errno) => return Err(errno),
+        }
+    }
+}
+
+pub fn restore(blob: &[u8]) -> Result<Pid, isize> {
+    syscall(SYS_RESTORE, &[blob.as_ptr() as usize, blob.len()]).map(Pid::from_raw)
+}
+
+// argc and argv as laid out on the initial stack by the kernel
+struct Args {
+    argc: Cell<usize>,
+    argv: Cell<*const *const u8>,
+}
+
+unsafe impl Sync for Args {}
+
+static ARGS: Args = Args {
+    argc: Cell::new(0),
+    argv: Cell::new(ptr::null()),
+};
+
+pub(crate) fn init_args(argc: usize, argv: *const *const u8) {
+    ARGS.argc.set(argc);
+    ARGS.argv.set(argv);
+}
+
+// Arguments the process was started with, the program name first
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let argv = ARGS.argv.get();
+    (0..ARGS.argc.get()).map(move |i| {
+        let arg = unsafe { CStr::from_ptr(*argv.add(i) as *const _) };
+        arg.to_str().unwrap_or("")
+    })
+}
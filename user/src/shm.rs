@@ -0,0 +1,14 @@
+use abi::{SYS_SHM_ATTACH, SYS_SHM_DETACH};
+
+use crate::syscall::syscall;
+
+// Maps the shared memory segment under `key`, creating it zeroed if nobody has it attached.
+// Everybody attaching a key has to ask for the same size. Returns the address it is mapped at.
+pub fn attach(key: usize, size: usize) -> Result<*mut u8, isize> {
+    syscall(SYS_SHM_ATTACH, &[key, size]).map(|addr| addr as *mut u8)
+}
+
+// Unmaps a segment `attach` returned; its memory goes away with the last process detaching it
+pub fn detach(addr: *mut u8) -> Result<(), isize> {
+    syscall(SYS_SHM_DETACH, &[addr as usize]).map(|_| ())
+}
@@ -0,0 +1,36 @@
+use core::{arch::naked_asm, fmt::Write, panic::PanicInfo};
+
+use crate::{
+    print, println,
+    process::{exit, init_args},
+};
+
+unsafe extern "Rust" {
+    fn main();
+}
+
+// The kernel enters here with sp pointing at argc, followed by argv
+#[unsafe(naked)]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".text.start")]
+unsafe extern "C" fn _start() -> ! {
+    naked_asm!(
+        "mv a0, sp",
+        "call {start}",
+        start = sym start,
+    );
+}
+
+extern "C" fn start(sp: *const usize) -> ! {
+    unsafe {
+        init_args(*sp, sp.add(1) as *const *const u8);
+        main();
+    }
+    exit(0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{info}");
+    exit(1);
+}
@@ -0,0 +1,28 @@
+use core::arch::asm;
+
+// Number in a7, arguments in a0-a5, result or negated errno in a0
+pub fn syscall(number: usize, args: &[usize]) -> Result<usize, isize> {
+    let mut regs = [0; 6];
+    regs[..args.len()].copy_from_slice(args);
+
+    let ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") regs[0] => ret,
+            in("a1") regs[1],
+            in("a2") regs[2],
+            in("a3") regs[3],
+            in("a4") regs[4],
+            in("a5") regs[5],
+            in("a7") number,
+        );
+    }
+
+    // errno values are small, so anything in the last page of the address space is an error
+    if (-4095..0).contains(&ret) {
+        Err(-ret)
+    } else {
+        Ok(ret as usize)
+    }
+}
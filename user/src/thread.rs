@@ -0,0 +1,79 @@
+use alloc::{boxed::Box, vec};
+use core::fmt;
+
+use abi::{PID_INDEX_BITS, SYS_THREAD_CREATE, SYS_THREAD_EXIT, SYS_THREAD_JOIN};
+
+use crate::syscall::syscall;
+
+const STACK_SIZE: usize = 16 * 1024;
+
+// Same layout as Pid, numbered separately
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tid(usize);
+
+impl Tid {
+    pub fn as_raw(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let index = self.0 & ((1 << PID_INDEX_BITS) - 1);
+        write!(f, "{}.{}", index, self.0 >> PID_INDEX_BITS)
+    }
+}
+
+pub struct JoinHandle {
+    tid: Tid,
+}
+
+impl JoinHandle {
+    pub fn tid(&self) -> Tid {
+        self.tid
+    }
+
+    // Waits for the thread to finish and returns the code it passed to `exit`, 0 if it returned
+    pub fn join(self) -> Result<usize, isize> {
+        syscall(SYS_THREAD_JOIN, &[self.tid.0])
+    }
+}
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+// Runs `f` in a new thread of the current process; EAGAIN once the process has as many threads
+// as the kernel allows. Its stack comes from the heap and is never given back, since a thread
+// nobody joins may still be on it.
+pub fn spawn<F>(f: F) -> Result<JoinHandle, isize>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = vec![0u8; STACK_SIZE].leak();
+    let sp = (stack.as_ptr() as usize + STACK_SIZE) & !0xf;
+
+    let main: Box<ThreadMain> = Box::new(Box::new(f));
+    let arg = Box::into_raw(main);
+
+    match syscall(
+        SYS_THREAD_CREATE,
+        &[thread_start as usize, sp, arg as usize],
+    ) {
+        Ok(raw) => Ok(JoinHandle { tid: Tid(raw) }),
+        Err(errno) => {
+            drop(unsafe { Box::from_raw(arg) });
+            Err(errno)
+        }
+    }
+}
+
+// Ends the current thread; the process goes on until its last thread exits
+pub fn exit(code: usize) -> ! {
+    let _ = syscall(SYS_THREAD_EXIT, &[code]);
+    unreachable!();
+}
+
+extern "C" fn thread_start(arg: *mut ThreadMain) -> ! {
+    let main = unsafe { Box::from_raw(arg) };
+    main();
+    exit(0);
+}
@@ -0,0 +1,79 @@
+use core::{
+    arch::asm,
+    ops::{Add, AddAssign},
+    time::Duration,
+};
+
+use abi::{SYS_SLEEP, TIMEBASE_FREQ};
+
+use crate::{process::yield_now, syscall::syscall};
+
+fn get_time() -> u64 {
+    let (mut hi, mut lo, mut tmp): (u32, u32, u32);
+    loop {
+        unsafe {
+            asm!("rdtimeh {0}", out(reg) hi, options(nomem, nostack));
+            asm!("rdtime  {0}", out(reg) lo, options(nomem, nostack));
+            asm!("rdtimeh {0}", out(reg) tmp, options(nomem, nostack));
+        }
+        if hi == tmp {
+            break;
+        }
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+const fn duration_to_ticks(duration: Duration) -> u64 {
+    duration.as_secs() * TIMEBASE_FREQ
+        + duration.subsec_nanos() as u64 * TIMEBASE_FREQ / 1_000_000_000
+}
+
+const fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_micros(ticks * 1_000_000 / TIMEBASE_FREQ)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(get_time())
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + duration_to_ticks(rhs))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+pub fn sleep(duration: Duration) {
+    let micros = duration.as_micros().min(usize::MAX as u128) as usize;
+    let _ = syscall(SYS_SLEEP, &[micros]);
+}
+
+// Like the kernel's: a deadline already in the past still gives up the CPU
+pub fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline <= now {
+        yield_now();
+    } else {
+        sleep(deadline.duration_since(now));
+    }
+}